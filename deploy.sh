@@ -2,4 +2,19 @@
 cargo +stable build --release
 ssh "root@$1.lichess.ovh" mv /usr/local/bin/lila-websocket /usr/local/bin/lila-websocket.bak || (echo "first deploy on this server? comment out this line" && false)
 scp ./target/release/lila-websocket "root@$1.lichess.ovh":/usr/local/bin/lila-websocket
-ssh "root@$1.lichess.ovh" systemctl restart lila-websocket
+scp ./lila-websocket@.service "root@$1.lichess.ovh":/etc/systemd/system/lila-websocket@.service
+
+# Start the new binary next to the old one, then let the old one drain.
+ssh "root@$1.lichess.ovh" sh -e <<'DEPLOY'
+systemctl daemon-reload
+if systemctl is-active --quiet lila-websocket@9664; then old=lila-websocket@9664; new=9665; else old=lila-websocket@9665; new=9664; fi
+# The single instance from before there were two holds port 9664.
+if systemctl is-active --quiet lila-websocket; then old=lila-websocket; new=9665; fi
+systemctl start "lila-websocket@$new"
+# Metrics are served on port 1$new once the Websocket port is bound.
+for i in $(seq 30); do curl -sf "http://127.0.0.1:1$new/metrics" > /dev/null && break; sleep 1; done
+curl -sf "http://127.0.0.1:1$new/metrics" > /dev/null || (echo "lila-websocket@$new did not come up" && false)
+systemctl stop "$old"
+# Keep it from taking port 9664 again on boot.
+if [ "$old" = lila-websocket ]; then systemctl disable lila-websocket; fi
+DEPLOY
@@ -0,0 +1,305 @@
+use std::cmp::min;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use smallvec::SmallVec;
+use ws::{ErrorKind, Frame, OpCode};
+
+/// Bytes that are left out at the end of each compressed message.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Limit for decompressed messages from clients. Larger messages are
+/// rejected anyway.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Server side settings for the permessage-deflate extension (RFC 7692),
+/// shared by all connections.
+///
+/// zlib compressors take 128 KiB for their hash tables plus the window, so
+/// the number of connections with compression is limited.
+#[derive(Debug)]
+pub struct DeflateSettings {
+    max_window_bits: u8, // 9 to 15
+    min_size: usize, // smaller messages are sent uncompressed
+    max_connections: usize,
+    connections: AtomicUsize,
+}
+
+impl DeflateSettings {
+    pub fn new(max_window_bits: u8, min_size: usize, max_connections: usize) -> DeflateSettings {
+        DeflateSettings {
+            max_window_bits,
+            min_size,
+            max_connections,
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of connections that currently use compression.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+/// Accept the first acceptable permessage-deflate offer of a client.
+/// Returns the compression state of the connection and the extension for the
+/// response, or `None` if no offer is acceptable or too many connections
+/// already use compression.
+pub fn negotiate(offers: &[&str], settings: &'static DeflateSettings) -> Option<(Deflate, String)> {
+    let (window_bits, no_context_takeover, response) = offers.iter().filter_map(|offer| accept(offer, settings)).next()?;
+    let slot = Slot::take(settings)?;
+    Some((Deflate {
+        com: None,
+        dec: None,
+        window_bits,
+        no_context_takeover,
+        min_size: settings.min_size,
+        inflating: None,
+        fragments: Vec::new(),
+        _slot: slot,
+    }, response))
+}
+
+/// Window bits, whether to reset the compressor after each message, and
+/// the extension for the response.
+fn accept(offer: &str, settings: &DeflateSettings) -> Option<(u8, bool, String)> {
+    let mut params = offer.split(';').map(|p| p.trim());
+    if params.next() != Some("permessage-deflate") {
+        return None;
+    }
+
+    let mut window_bits = settings.max_window_bits;
+    let mut no_context_takeover = false;
+    let mut seen: SmallVec<[&str; 4]> = SmallVec::new();
+
+    for param in params {
+        let mut kv = param.splitn(2, '=');
+        let name = kv.next().unwrap().trim();
+        let value = kv.next().map(|v| v.trim().trim_matches('"'));
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+
+        match (name, value) {
+            ("server_no_context_takeover", None) => no_context_takeover = true,
+            ("client_no_context_takeover", None) => (), // decompression works either way
+            ("server_max_window_bits", Some(bits)) => {
+                // zlib can not produce raw deflate streams with 8 bit windows.
+                let bits: u8 = bits.parse().ok().filter(|b| (9..=15).contains(b))?;
+                window_bits = min(window_bits, bits);
+            }
+            ("client_max_window_bits", _) => (), // always decompress with the largest window
+            _ => return None,
+        }
+    }
+
+    let mut response = "permessage-deflate".to_owned();
+    if no_context_takeover {
+        response.push_str("; server_no_context_takeover");
+    }
+    if window_bits < 15 {
+        write!(response, "; server_max_window_bits={}", window_bits).expect("write to string");
+    }
+
+    Some((window_bits, no_context_takeover, response))
+}
+
+/// Counts a connection with compression until dropped.
+#[derive(Debug)]
+struct Slot(&'static AtomicUsize);
+
+impl Slot {
+    fn take(settings: &'static DeflateSettings) -> Option<Slot> {
+        if settings.connections.fetch_add(1, Ordering::Relaxed) >= settings.max_connections {
+            settings.connections.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Slot(&settings.connections))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Compression state of a connection that negotiated permessage-deflate.
+///
+/// The zlib streams are created on first use, because many clients never
+/// send compressed messages, and some never get a message large enough to
+/// be compressed.
+pub struct Deflate {
+    com: Option<Compress>,
+    dec: Option<Decompress>,
+    window_bits: u8,
+    no_context_takeover: bool,
+    min_size: usize,
+    inflating: Option<OpCode>, // fragmented compressed message in progress
+    fragments: Vec<u8>,
+    _slot: Slot,
+}
+
+impl Deflate {
+    /// Compress an outgoing message, unless it is small.
+    pub fn on_send_frame(&mut self, mut frame: Frame) -> Frame {
+        if !frame.is_control() && frame.payload().len() >= self.min_size {
+            let compressed = self.compress(frame.payload());
+            *frame.payload_mut() = compressed;
+            frame.set_rsv1(true);
+        }
+        frame
+    }
+
+    /// Decompress an incoming message. Returns `None` while waiting for
+    /// more fragments.
+    #[allow(clippy::result_large_err)] // ws::Result
+    pub fn on_frame(&mut self, mut frame: Frame) -> ws::Result<Option<Frame>> {
+        if frame.has_rsv2() || frame.has_rsv3() || (frame.has_rsv1() && (frame.is_control() || frame.opcode() == OpCode::Continue)) {
+            return Err(ws::Error::new(ErrorKind::Protocol, "unexpected reserved bits"));
+        }
+
+        if frame.opcode() == OpCode::Continue {
+            let opcode = match self.inflating {
+                Some(opcode) => opcode,
+                None => return Ok(Some(frame)), // uncompressed fragment
+            };
+            if self.fragments.len() + frame.payload().len() > MAX_MESSAGE_SIZE {
+                return Err(ws::Error::new(ErrorKind::Capacity, "compressed message too long"));
+            }
+            self.fragments.extend_from_slice(frame.payload());
+            if !frame.is_final() {
+                return Ok(None);
+            }
+            self.inflating = None;
+            let data = std::mem::take(&mut self.fragments);
+            return Ok(Some(Frame::message(self.decompress(data)?, opcode, true)));
+        }
+
+        if !frame.has_rsv1() {
+            return Ok(Some(frame));
+        }
+
+        frame.set_rsv1(false);
+        if !frame.is_final() {
+            self.inflating = Some(frame.opcode());
+            self.fragments = frame.into_data();
+            return Ok(None);
+        }
+
+        let opcode = frame.opcode();
+        Ok(Some(Frame::message(self.decompress(frame.into_data())?, opcode, true)))
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let window_bits = self.window_bits;
+        let com = self.com.get_or_insert_with(|| Compress::new_with_window_bits(Compression::fast(), false, window_bits));
+
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = com.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let consumed = (com.total_in() - start) as usize;
+            com.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync).expect("deflate");
+            if (com.total_in() - start) as usize == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.no_context_takeover {
+            com.reset();
+        }
+        out
+    }
+
+    #[allow(clippy::result_large_err)] // ws::Result
+    fn decompress(&mut self, mut data: Vec<u8>) -> ws::Result<Vec<u8>> {
+        data.extend_from_slice(&TAIL);
+        let dec = self.dec.get_or_insert_with(|| Decompress::new_with_window_bits(false, 15));
+        let mut out = Vec::with_capacity(min(data.len() * 4, MAX_MESSAGE_SIZE));
+        let start = dec.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                if out.len() >= MAX_MESSAGE_SIZE {
+                    return Err(ws::Error::new(ErrorKind::Capacity, "decompressed message too long"));
+                }
+                out.reserve(min(out.capacity(), MAX_MESSAGE_SIZE - out.len()));
+            }
+            let consumed = (dec.total_in() - start) as usize;
+            let written = out.len();
+            let status = dec.decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|err| ws::Error::new(ErrorKind::Protocol, format!("inflate: {}", err)))?;
+            let done = (dec.total_in() - start) as usize == data.len();
+            if status == Status::StreamEnd || (done && out.len() < out.capacity()) {
+                break;
+            }
+            if !done && out.len() == written && (dec.total_in() - start) as usize == consumed {
+                return Err(ws::Error::new(ErrorKind::Protocol, "inflate: no progress"));
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_connections: usize) -> &'static DeflateSettings {
+        Box::leak(Box::new(DeflateSettings::new(12, 8, max_connections)))
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let settings = settings(10);
+        let (_, ext) = negotiate(&["permessage-deflate; client_max_window_bits"], settings).unwrap();
+        assert_eq!(ext, "permessage-deflate; server_max_window_bits=12");
+
+        let (_, ext) = negotiate(&["permessage-deflate; server_max_window_bits=8", "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"], settings).unwrap();
+        assert_eq!(ext, "permessage-deflate; server_no_context_takeover; server_max_window_bits=10");
+
+        assert!(negotiate(&["permessage-deflate; server_no_context_takeover; server_no_context_takeover"], settings).is_none());
+        assert!(negotiate(&["x-webkit-deflate-frame"], settings).is_none());
+        assert_eq!(settings.connections(), 0);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let settings = settings(1);
+        let (deflate, _) = negotiate(&["permessage-deflate"], settings).unwrap();
+        assert!(negotiate(&["permessage-deflate"], settings).is_none());
+        assert_eq!(settings.connections(), 1);
+
+        drop(deflate);
+        assert_eq!(settings.connections(), 0);
+        assert!(negotiate(&["permessage-deflate"], settings).is_some());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut deflate, _) = negotiate(&["permessage-deflate"], settings(10)).unwrap();
+        assert!(deflate.com.is_none() && deflate.dec.is_none()); // not yet used
+        let msg = r#"{"t":"fen","d":{"id":"abcdefgh","fen":"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR","lm":"e2e4"}}"#;
+
+        for _ in 0..3 {
+            let sent = deflate.on_send_frame(Frame::message(msg.as_bytes().to_vec(), OpCode::Text, true));
+            assert!(sent.has_rsv1());
+            assert!(sent.payload().len() < msg.len());
+
+            // Decompress our own output, as if sent by a client.
+            let received = deflate.on_frame(sent).unwrap().unwrap();
+            assert!(!received.has_rsv1());
+            assert_eq!(received.payload().as_slice(), msg.as_bytes());
+        }
+
+        let small = deflate.on_send_frame(Frame::message(b"0".to_vec(), OpCode::Text, true));
+        assert!(!small.has_rsv1());
+    }
+}
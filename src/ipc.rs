@@ -1,12 +1,56 @@
 use std::fmt;
+use std::fmt::Write as _;
 
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use smallvec::SmallVec;
 use std::collections::HashMap;
 
-use crate::model::{Flag, GameId, Sri, UserId, InvalidUserId};
+use crate::model::{Flag, GameId, NodeId, Sri, UserId, InvalidUserId};
 
+/// Message from lila that could not be parsed.
 #[derive(Debug)]
-pub struct IpcError;
+pub struct IpcError {
+    pub tag: String,
+    pub reason: String,
+}
+
+impl IpcError {
+    fn new<R: Into<String>>(tag: &str, reason: R) -> IpcError {
+        IpcError {
+            tag: tag.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.tag, self.reason)
+    }
+}
+
+/// Wire format of messages, negotiated with lila at startup. Version 0 is
+/// the space separated text protocol, version 1 wraps each message in a
+/// JSON envelope: `{"v":1,"t":"<tag>","d":<data>}`. Version 2 adds asks,
+/// which carry an `"id"` that lila echoes in its reply.
+///
+/// When running multiple nodes, messages to lila are marked with the node
+/// id, as a `@<node> ` prefix in the text protocol, or as field `"n"` of
+/// the envelope. Every node receives everything from lila, so lila marks
+/// replies and hello answers with the node they are meant for in the same
+/// way.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    Text,
+    Json,
+}
+
+impl Encoding {
+    pub fn from_version(version: u8) -> Encoding {
+        if version >= 1 { Encoding::Json } else { Encoding::Text }
+    }
+}
 
 /// Messages we receive from lila.
 #[derive(Debug)]
@@ -35,56 +79,214 @@ pub enum LilaOut<'a> {
         uid: UserId,
     },
     MoveLatency(u32),
+    Hello {
+        node: Option<NodeId>,
+        version: u8,
+    },
+    Resync,
+    Reply {
+        node: Option<NodeId>,
+        id: u64,
+        payload: &'a str,
+    },
 }
 
 impl<'a> LilaOut<'a> {
+    /// Parse a message in either encoding.
     pub fn parse(s: &'a str) -> Result<LilaOut<'a>, IpcError> {
+        if s.starts_with('{') {
+            return LilaOut::parse_json(s);
+        }
+
+        // Node that the message is meant for.
+        let (node, s) = match s.strip_prefix('@') {
+            Some(rest) => {
+                let mut node_and_msg = rest.splitn(2, ' ');
+                let node = node_and_msg.next().unwrap().parse().map_err(|_| IpcError::new("?", "invalid node"))?;
+                (Some(node), node_and_msg.next().unwrap_or(""))
+            }
+            None => (None, s),
+        };
+
         let mut tag_and_args = s.splitn(2, ' ');
-        Ok(match (tag_and_args.next().unwrap(), tag_and_args.next()) {
-            ("move", Some(args)) => {
-                let mut args = args.splitn(3, ' ');
+        let tag = tag_and_args.next().unwrap();
+        let maybe_args = tag_and_args.next();
+        let args = || maybe_args.ok_or_else(|| IpcError::new(tag, "missing arguments"));
+        let missing = |what: &str| IpcError::new(tag, format!("missing {}", what));
+        let invalid = |what: &str| IpcError::new(tag, format!("invalid {}", what));
+
+        Ok(match tag {
+            "move" => {
+                let mut args = args()?.splitn(3, ' ');
                 LilaOut::Move {
-                    game: args.next().unwrap().parse().map_err(|_| IpcError)?,
-                    last_uci: args.next().ok_or(IpcError)?,
-                    fen: args.next().ok_or(IpcError)?,
+                    game: args.next().unwrap().parse().map_err(|_| invalid("game id"))?,
+                    last_uci: args.next().ok_or_else(|| missing("last uci"))?,
+                    fen: args.next().ok_or_else(|| missing("fen"))?,
                 }
             },
-            ("tell/user", Some(args)) | ("tell/users", Some(args)) => {
-                let mut args = args.splitn(2, ' ');
+            "tell/user" | "tell/users" => {
+                let mut args = args()?.splitn(2, ' ');
                 let maybe_users: Result<_, InvalidUserId> = args.next().unwrap().split(',').map(UserId::new).collect();
                 LilaOut::TellUsers {
-                    users: maybe_users.map_err(|_| IpcError)?,
-                    payload: args.next().ok_or(IpcError)?,
+                    users: maybe_users.map_err(|_| invalid("user id"))?,
+                    payload: args.next().ok_or_else(|| missing("payload"))?,
                 }
             },
-            ("tell/all", Some(payload)) => {
-                LilaOut::TellAll { payload }
+            "tell/all" => {
+                LilaOut::TellAll { payload: args()? }
             },
-            ("tell/flag", Some(args)) => {
-                let mut args = args.splitn(2, ' ');
+            "tell/flag" => {
+                let mut args = args()?.splitn(2, ' ');
                 LilaOut::TellFlag {
-                    flag: args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?,
-                    payload: args.next().ok_or(IpcError)?,
+                    flag: args.next().unwrap().parse().map_err(|_| invalid("flag"))?,
+                    payload: args.next().ok_or_else(|| missing("payload"))?,
                 }
             },
-            ("tell/sri", Some(args)) => {
-                let mut args = args.splitn(2, ' ');
+            "tell/sri" => {
+                let mut args = args()?.splitn(2, ' ');
                 LilaOut::TellSri {
-                    sri: args.next().unwrap().parse().map_err(|_| IpcError)?,
-                    payload: args.next().ok_or(IpcError)?,
+                    sri: args.next().unwrap().parse().map_err(|_| invalid("sri"))?,
+                    payload: args.next().ok_or_else(|| missing("payload"))?,
                 }
             },
-            ("disconnect/user", Some(uid)) => {
+            "disconnect/user" => {
                 LilaOut::DisconnectUser {
-                    uid: UserId::new(uid).map_err(|_| IpcError)?,
+                    uid: UserId::new(args()?).map_err(|_| invalid("user id"))?,
                 }
             }
-            ("mlat", Some(value)) => {
-                LilaOut::MoveLatency(value.parse().map_err(|_| IpcError)?)
+            "mlat" => {
+                LilaOut::MoveLatency(args()?.parse().map_err(|_| invalid("number"))?)
             },
-            _ => return Err(IpcError),
+            "hello" => {
+                LilaOut::Hello {
+                    node,
+                    version: args()?.parse().map_err(|_| invalid("version"))?,
+                }
+            },
+            "resync" | "boot" => LilaOut::Resync,
+            "reply" => {
+                let mut args = args()?.splitn(2, ' ');
+                LilaOut::Reply {
+                    node,
+                    id: args.next().unwrap().parse().map_err(|_| invalid("ask id"))?,
+                    payload: args.next().ok_or_else(|| missing("payload"))?,
+                }
+            },
+            _ => return Err(IpcError::new(tag, "unknown tag")),
         })
     }
+
+    fn parse_json(s: &'a str) -> Result<LilaOut<'a>, IpcError> {
+        #[derive(Deserialize)]
+        struct Envelope<'a> {
+            v: u8,
+            t: &'a str,
+            n: Option<NodeId>,
+            id: Option<u64>,
+            #[serde(borrow)]
+            d: Option<&'a RawValue>,
+        }
+
+        #[derive(Deserialize)]
+        struct Move<'a> {
+            game: GameId,
+            uci: &'a str,
+            fen: &'a str,
+        }
+
+        #[derive(Deserialize)]
+        struct TellUsers<'a> {
+            users: Vec<UserId>,
+            #[serde(borrow)]
+            payload: &'a RawValue,
+        }
+
+        #[derive(Deserialize)]
+        struct TellFlag<'a> {
+            flag: Flag,
+            #[serde(borrow)]
+            payload: &'a RawValue,
+        }
+
+        #[derive(Deserialize)]
+        struct TellSri<'a> {
+            sri: Sri,
+            #[serde(borrow)]
+            payload: &'a RawValue,
+        }
+
+        let envelope: Envelope = serde_json::from_str(s)
+            .map_err(|err| IpcError::new("?", format!("invalid envelope: {}", err)))?;
+        let tag = envelope.t;
+        if envelope.v < 1 || envelope.v > 2 {
+            return Err(IpcError::new(tag, format!("unsupported version {}", envelope.v)));
+        }
+
+        fn data<'a, T: Deserialize<'a>>(tag: &str, d: Option<&'a RawValue>) -> Result<T, IpcError> {
+            let d = d.ok_or_else(|| IpcError::new(tag, "missing data"))?;
+            serde_json::from_str(d.get()).map_err(|err| IpcError::new(tag, err.to_string()))
+        }
+
+        Ok(match tag {
+            "move" => {
+                let d: Move = data(tag, envelope.d)?;
+                LilaOut::Move { game: d.game, last_uci: d.uci, fen: d.fen }
+            }
+            "tell/users" => {
+                let d: TellUsers = data(tag, envelope.d)?;
+                LilaOut::TellUsers { users: d.users.into_iter().collect(), payload: d.payload.get() }
+            }
+            "tell/all" => {
+                let payload: &RawValue = data(tag, envelope.d)?;
+                LilaOut::TellAll { payload: payload.get() }
+            }
+            "tell/flag" => {
+                let d: TellFlag = data(tag, envelope.d)?;
+                LilaOut::TellFlag { flag: d.flag, payload: d.payload.get() }
+            }
+            "tell/sri" => {
+                let d: TellSri = data(tag, envelope.d)?;
+                LilaOut::TellSri { sri: d.sri, payload: d.payload.get() }
+            }
+            "disconnect/user" => LilaOut::DisconnectUser { uid: data(tag, envelope.d)? },
+            "mlat" => LilaOut::MoveLatency(data(tag, envelope.d)?),
+            "hello" => LilaOut::Hello { node: envelope.n, version: data(tag, envelope.d)? },
+            "resync" | "boot" => LilaOut::Resync,
+            "reply" => {
+                let payload: &RawValue = data(tag, envelope.d)?;
+                LilaOut::Reply {
+                    node: envelope.n,
+                    id: envelope.id.ok_or_else(|| IpcError::new(tag, "missing ask id"))?,
+                    payload: payload.get(),
+                }
+            }
+            _ => return Err(IpcError::new(tag, "unknown tag")),
+        })
+    }
+}
+
+/// Join messages into a single multi-line frame, so that they can be sent
+/// at once. Line breaks within messages can only be insignificant JSON
+/// whitespace and are replaced.
+pub fn join_batch(msgs: &[String]) -> String {
+    let mut frame = String::with_capacity(msgs.iter().map(|msg| msg.len() + 1).sum());
+    for msg in msgs {
+        if !frame.is_empty() {
+            frame.push('\n');
+        }
+        if msg.contains('\n') {
+            frame.push_str(&msg.replace('\n', " "));
+        } else {
+            frame.push_str(msg);
+        }
+    }
+    frame
+}
+
+/// Split a frame into the individual messages. A frame with a single message
+/// is just that message.
+pub fn split_batch(frame: &str) -> impl Iterator<Item = &str> {
+    frame.split('\n').filter(|msg| !msg.is_empty())
 }
 
 /// Messages we send to lila.
@@ -100,6 +302,105 @@ pub enum LilaIn<'a> {
     Lags(&'a HashMap::<UserId, u32>),
     Friends(&'a UserId),
     TellSri(&'a Sri, Option<&'a UserId>, &'a str),
+    Hello(u8),
+    /// Message that lila should answer with a reply carrying the same id.
+    Ask(u64, &'a LilaIn<'a>),
+}
+
+impl<'a> LilaIn<'a> {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            LilaIn::Connect(_) => "connect",
+            LilaIn::Disconnect(_) => "disconnect",
+            LilaIn::DisconnectAll => "disconnect/all",
+            LilaIn::Notified(_) => "notified",
+            LilaIn::Watch(_) => "watch",
+            LilaIn::Unwatch(_) => "unwatch",
+            LilaIn::Connections(_) => "connections",
+            LilaIn::Lags(_) => "lags",
+            LilaIn::Friends(_) => "friends",
+            LilaIn::TellSri(..) => "tell/sri",
+            LilaIn::Hello(_) => "hello",
+            LilaIn::Ask(..) => "ask",
+        }
+    }
+
+    pub fn encode(&self, encoding: Encoding, node: Option<&NodeId>) -> String {
+        match (encoding, node) {
+            (Encoding::Text, None) => self.to_string(),
+            (Encoding::Text, Some(node)) => format!("@{} {}", node, self),
+            (Encoding::Json, node) => self.to_json_string(node),
+        }
+    }
+
+    fn to_json_string(&self, n: Option<&NodeId>) -> String {
+        #[derive(Serialize)]
+        struct Envelope<'a, T> {
+            v: u8,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            n: Option<&'a NodeId>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            id: Option<u64>,
+            t: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            d: Option<T>,
+        }
+
+        #[derive(Serialize)]
+        struct TellSri<'a> {
+            sri: &'a Sri,
+            user: Option<&'a UserId>,
+            payload: &'a RawValue,
+        }
+
+        fn envelope<T: Serialize>(n: Option<&NodeId>, id: Option<u64>, t: &'static str, d: Option<T>) -> String {
+            let v = if id.is_some() { 2 } else { 1 };
+            serde_json::to_string(&Envelope { v, n, id, t, d }).expect("serialize for lila")
+        }
+
+        let (msg, id) = match *self {
+            LilaIn::Ask(id, msg) => (msg, Some(id)),
+            _ => (self, None),
+        };
+
+        let t = msg.tag();
+        match *msg {
+            LilaIn::Connect(uid) | LilaIn::Disconnect(uid) |
+            LilaIn::Notified(uid) | LilaIn::Friends(uid) => envelope(n, id, t, Some(uid)),
+            LilaIn::DisconnectAll => envelope::<()>(n, id, t, None),
+            LilaIn::Watch(game) | LilaIn::Unwatch(game) => envelope(n, id, t, Some(game)),
+            LilaIn::Connections(count) => envelope(n, id, t, Some(count)),
+            LilaIn::Lags(lags) => envelope(n, id, t, Some(lags)),
+            LilaIn::TellSri(sri, user, payload) => {
+                // The payload has been parsed as JSON before.
+                let payload: &RawValue = serde_json::from_str(payload).expect("json payload");
+                envelope(n, id, t, Some(TellSri { sri, user, payload }))
+            }
+            LilaIn::Hello(version) => envelope(n, id, t, Some(version)),
+            LilaIn::Ask(..) => unreachable!("nested ask"),
+        }
+    }
+}
+
+/// Add lags to a queued lags message. Later entries take precedence.
+pub fn merge_lags(queued: &mut String, lags: &HashMap<UserId, u32>, encoding: Encoding, node: Option<&NodeId>) {
+    match encoding {
+        Encoding::Text => {
+            for (uid, lag) in lags.iter() {
+                write!(queued, "{}:{},", uid, lag).expect("write to string");
+            }
+        }
+        Encoding::Json => {
+            #[derive(Deserialize)]
+            struct Envelope {
+                d: HashMap<UserId, u32>,
+            }
+
+            let mut merged = serde_json::from_str::<Envelope>(queued).expect("queued lags").d;
+            merged.extend(lags.iter().map(|(uid, lag)| (uid.clone(), *lag)));
+            *queued = LilaIn::Lags(&merged).encode(encoding, node);
+        }
+    }
 }
 
 impl<'a> fmt::Display for LilaIn<'a> {
@@ -112,6 +413,7 @@ impl<'a> fmt::Display for LilaIn<'a> {
             LilaIn::Watch(game) => write!(f, "watch {}", game),
             LilaIn::Unwatch(game) => write!(f, "unwatch {}", game),
             LilaIn::Connections(n) => write!(f, "connections {}", n),
+            LilaIn::Ask(id, msg) => write!(f, "ask {} {}", id, msg),
             LilaIn::Lags(lags) => {
                 write!(f, "lags ")?;
                 for (uid, lag) in lags.iter() { 
@@ -122,6 +424,112 @@ impl<'a> fmt::Display for LilaIn<'a> {
             LilaIn::Friends(uid) => write!(f, "friends {}", uid),
             LilaIn::TellSri(sri, uid, payload) =>
                 write!(f, "tell/sri {} {} {}", sri, uid.map_or("-", |u| u.as_str()), payload),
+            LilaIn::Hello(version) => write!(f, "hello {}", version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let msgs = vec!["connect foo".to_owned(), "tell/sri abc - {\n\"t\":\"evalGet\"}".to_owned()];
+        let frame = join_batch(&msgs);
+        assert_eq!(frame, "connect foo\ntell/sri abc - { \"t\":\"evalGet\"}");
+        assert_eq!(split_batch(&frame).collect::<Vec<_>>(), vec!["connect foo", "tell/sri abc - { \"t\":\"evalGet\"}"]);
+
+        assert_eq!(split_batch("mlat 42").collect::<Vec<_>>(), vec!["mlat 42"]);
+        assert!(split_batch("mlat 1\nmlat 2").all(|msg| LilaOut::parse(msg).is_ok()));
+    }
+
+    #[test]
+    fn test_parse_json() {
+        match LilaOut::parse(r#"{"v":1,"t":"tell/users","d":{"users":["Foo","bar"],"payload":{"t":"x"}}}"#) {
+            Ok(LilaOut::TellUsers { users, payload }) => {
+                assert_eq!(users.as_slice(), &[UserId::new("foo").unwrap(), UserId::new("bar").unwrap()]);
+                assert_eq!(payload, r#"{"t":"x"}"#);
+            }
+            res => panic!("unexpected: {:?}", res),
+        }
+
+        match LilaOut::parse(r#"{"v":1,"t":"move","d":{"game":"abcdefgh","uci":"e2e4","fen":"8/8/8/8/8/8/8/8"}}"#) {
+            Ok(LilaOut::Move { game, last_uci, fen }) => {
+                assert_eq!(game, "abcdefgh".parse().unwrap());
+                assert_eq!(last_uci, "e2e4");
+                assert_eq!(fen, "8/8/8/8/8/8/8/8");
+            }
+            res => panic!("unexpected: {:?}", res),
+        }
+
+        let err = LilaOut::parse(r#"{"v":1,"t":"mlat","d":"fast"}"#).unwrap_err();
+        assert_eq!(err.tag, "mlat");
+        let err = LilaOut::parse(r#"{"v":3,"t":"mlat","d":1}"#).unwrap_err();
+        assert_eq!(err.reason, "unsupported version 3");
+    }
+
+    #[test]
+    fn test_ask_reply() {
+        let uid = UserId::new("foo").unwrap();
+        let friends = LilaIn::Friends(&uid);
+        let ask = LilaIn::Ask(7, &friends);
+        assert_eq!(ask.encode(Encoding::Text, None), "ask 7 friends foo");
+        assert_eq!(ask.encode(Encoding::Json, None), r#"{"v":2,"id":7,"t":"friends","d":"foo"}"#);
+
+        match LilaOut::parse(r#"{"v":2,"id":7,"t":"reply","d":{"t":"following_onlines"}}"#) {
+            Ok(LilaOut::Reply { node: None, id, payload }) => {
+                assert_eq!(id, 7);
+                assert_eq!(payload, r#"{"t":"following_onlines"}"#);
+            }
+            res => panic!("unexpected: {:?}", res),
         }
+        assert!(matches!(LilaOut::parse("reply 7 {}"), Ok(LilaOut::Reply { node: None, id: 7, payload: "{}" })));
+
+        // Replies to one of several nodes.
+        let node: NodeId = "khiaw-9664".parse().unwrap();
+        match LilaOut::parse("@khiaw-9664 reply 7 {}") {
+            Ok(LilaOut::Reply { node: Some(n), id: 7, payload: "{}" }) => assert_eq!(n, node),
+            res => panic!("unexpected: {:?}", res),
+        }
+        match LilaOut::parse(r#"{"v":2,"n":"khiaw-9664","t":"hello","d":2}"#) {
+            Ok(LilaOut::Hello { node: Some(n), version: 2 }) => assert_eq!(n, node),
+            res => panic!("unexpected: {:?}", res),
+        }
+        assert_eq!(LilaOut::parse(r#"{"v":2,"t":"reply","d":{}}"#).unwrap_err().reason, "missing ask id");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = LilaOut::parse("move abcdefgh e2e4").unwrap_err();
+        assert_eq!(err.tag, "move");
+        assert_eq!(err.reason, "missing fen");
+
+        assert!(matches!(LilaOut::parse("boot"), Ok(LilaOut::Resync)));
+        assert!(matches!(LilaOut::parse(r#"{"v":1,"t":"resync"}"#), Ok(LilaOut::Resync)));
+
+        let err = LilaOut::parse("tell/nobody").unwrap_err();
+        assert_eq!(err.to_string(), "tell/nobody: unknown tag");
+    }
+
+    #[test]
+    fn test_encode_json() {
+        let sri: Sri = "abc".parse().unwrap();
+        let msg = LilaIn::TellSri(&sri, None, r#"{"t":"evalGet"}"#);
+        assert_eq!(msg.encode(Encoding::Text, None), r#"tell/sri abc - {"t":"evalGet"}"#);
+        assert_eq!(msg.encode(Encoding::Json, None), r#"{"v":1,"t":"tell/sri","d":{"sri":"abc","user":null,"payload":{"t":"evalGet"}}}"#);
+        assert_eq!(LilaIn::DisconnectAll.encode(Encoding::Json, None), r#"{"v":1,"t":"disconnect/all"}"#);
+
+        let node: NodeId = "ws-2".parse().unwrap();
+        assert_eq!(LilaIn::DisconnectAll.encode(Encoding::Text, Some(&node)), "@ws-2 disconnect/all");
+        assert_eq!(LilaIn::Connections(3).encode(Encoding::Json, Some(&node)), r#"{"v":1,"n":"ws-2","t":"connections","d":3}"#);
+
+        let uid = UserId::new("foo").unwrap();
+        let mut lags = HashMap::new();
+        lags.insert(uid.clone(), 10);
+        let mut queued = LilaIn::Lags(&lags).encode(Encoding::Json, Some(&node));
+        lags.insert(uid, 20);
+        merge_lags(&mut queued, &lags, Encoding::Json, Some(&node));
+        assert_eq!(queued, r#"{"v":1,"n":"ws-2","t":"lags","d":{"foo":20}}"#);
     }
 }
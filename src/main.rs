@@ -3,12 +3,10 @@ use mongodb::db::ThreadedDatabase as _;
 use mongodb::coll::options::FindOptions;
 use bson::{doc, bson};
 
-use redis::Commands as _;
-
 use cookie::Cookie;
 use serde::{Serialize, Deserialize};
 
-use ws::{Handshake, Handler, Sender, Message, CloseCode};
+use ws::{Handshake, Handler, Sender, Message, CloseCode, Request, Response, Frame};
 use ws::util::Token;
 use mio_extras::timer::Timeout;
 
@@ -16,45 +14,186 @@ use structopt::StructOpt;
 
 use std::str;
 use std::mem;
-use std::cmp::max;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufRead as _, BufReader, Write as _};
+use std::path::PathBuf;
+use std::thread;
+use std::cmp::{max, min};
 use std::convert::TryInto;
-use std::net::IpAddr;
+use std::net::{IpAddr, TcpListener, TcpStream};
 use std::num::NonZeroU32;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::{HashMap, HashSet};
 use smallvec::SmallVec;
 
-use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
 use once_cell::sync::OnceCell;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use crossbeam::channel;
-use ratelimit_meter::KeyedRateLimiter;
+use rand::Rng as _;
+use rand::seq::SliceRandom as _;
+use signal_hook::iterator::Signals;
 
 mod model;
 mod ipc;
 mod util;
 mod analysis;
-
-use crate::model::{Flag, GameId, Sri, UserId};
-use crate::ipc::{LilaOut, LilaIn};
+mod transport;
+mod queue;
+mod outbox;
+mod deflate;
+mod replay;
+mod proxy;
+mod ratelimit;
+
+use crate::model::{Flag, GameId, NodeId, Sri, UserId};
+use crate::ipc::{LilaOut, LilaIn, Encoding};
+use crate::util::{Backoff, Sharded};
+use crate::queue::{LilaQueue, OverflowPolicy};
+use crate::outbox::{Outbox, OutboxPolicy, Payload};
+use crate::deflate::{Deflate, DeflateSettings};
+use crate::replay::{Replay, ReplaySettings};
+use crate::proxy::TrustedProxies;
+use crate::ratelimit::{Bucket, Client, Costs, Credits, RateLimiter};
+use crate::transport::{Transport, TransportKind, RedisTransport, RedisStreamTransport, UnixTransport, MemoryTransport, MemoryLila};
 
 #[derive(StructOpt, Clone)]
 struct Opt {
+    /// Id of this instance, when running several behind a load balancer
+    #[structopt(long = "node")]
+    node: Option<NodeId>,
     /// Binding address of Websocket server
     #[structopt(long = "bind", default_value = "127.0.0.1:9664")]
     bind: String,
+    /// Binding address of plain HTTP server for /metrics (none if omitted).
+    /// Do not expose it through the reverse proxy
+    #[structopt(long = "metrics-bind")]
+    metrics_bind: Option<String>,
+    /// How to exchange messages with lila: redis, redis-streams, unix or memory
+    #[structopt(long = "transport", default_value = "redis")]
+    transport: TransportKind,
     /// URI of redis server
     #[structopt(long = "redis", default_value = "redis://127.0.0.1/")]
     redis: String,
+    /// Consumer group for reading site-out with redis-streams (suffixed with
+    /// the node id, if any)
+    #[structopt(long = "stream-group", default_value = "lila-websocket")]
+    stream_group: String,
+    /// Path of Unix domain socket where lila is listening
+    #[structopt(long = "unix-socket", default_value = "/run/lila/site.sock", parse(from_os_str))]
+    unix_socket: PathBuf,
+    /// Number of messages queued for lila, beyond which the overflow policy
+    /// applies. Only a hard limit with the drop policy
+    #[structopt(long = "lila-queue-size", default_value = "100000")]
+    lila_queue_size: usize,
+    /// What to do when the queue for lila is full: keep, coalesce or drop
+    #[structopt(long = "lila-queue-overflow", default_value = "drop")]
+    lila_queue_overflow: OverflowPolicy,
+    /// Maximum number of queued messages to send to lila at once
+    #[structopt(long = "batch-size", default_value = "100")]
+    batch_size: usize,
+    /// Send batches as multi-line frames (lila must support these)
+    #[structopt(long = "batch-frames")]
+    batch_frames: bool,
+    /// Highest IPC encoding version to offer lila (0: text only, 1: JSON,
+    /// 2: JSON with asks)
+    #[structopt(long = "ipc-version", default_value = "0")]
+    ipc_version: u8,
+    /// Seconds without mlat from lila, after which clients are told that
+    /// lila is unavailable
+    #[structopt(long = "lila-timeout", default_value = "15")]
+    lila_timeout: u64,
+    /// Seconds to wait for lila to answer an ask, before telling the client
+    /// that it failed
+    #[structopt(long = "ask-timeout", default_value = "5")]
+    ask_timeout: u64,
     /// URI of mongodb with security collection
     #[structopt(long = "mongodb", default_value = "mongodb://127.0.0.1/")]
     mongodb: String,
+    /// Bytes that a client may fall behind, before further messages to it
+    /// are dropped
+    #[structopt(long = "max-backlog", default_value = "262144")]
+    max_backlog: u64,
+    /// Seconds that a client may stay behind, before it is disconnected
+    #[structopt(long = "slow-consumer-grace", default_value = "10")]
+    slow_consumer_grace: u64,
+    /// Bytes that a game watcher may fall behind, before it gets only the
+    /// latest position of each game
+    #[structopt(long = "conflate-backlog", default_value = "16384")]
+    conflate_backlog: u64,
+    /// Do not offer permessage-deflate compression to clients
+    #[structopt(long = "no-deflate")]
+    no_deflate: bool,
+    /// Largest compression window (9 to 15). Each compressor takes 128 KiB
+    /// plus 4 << bits bytes, so 144 KiB with the default
+    #[structopt(long = "deflate-window-bits", default_value = "12")]
+    deflate_window_bits: u8,
+    /// Most connections with compression at a time. Later connections are
+    /// not compressed
+    #[structopt(long = "max-deflate-connections", default_value = "10000")]
+    max_deflate_connections: usize,
+    /// Send messages smaller than this many bytes uncompressed
+    #[structopt(long = "deflate-min-size", default_value = "128")]
+    deflate_min_size: usize,
+    /// Seconds over which to close all sockets when shutting down
+    #[structopt(long = "shutdown-window", default_value = "30")]
+    shutdown_window: u64,
+    /// Clients closed during shutdown are told to wait up to this many
+    /// seconds before reconnecting
+    #[structopt(long = "reconnect-jitter", default_value = "10")]
+    reconnect_jitter: u64,
+    /// Number of threads for analysis requests
+    #[structopt(long = "analysis-threads", default_value = "4")]
+    analysis_threads: usize,
+    /// Maximum number of queued analysis requests, split among the threads,
+    /// before further requests fail immediately
+    #[structopt(long = "analysis-queue-size", default_value = "1000")]
+    analysis_queue_size: usize,
+    /// Number of recent messages to each sri that are kept, so that clients
+    /// can resume after reconnecting
+    #[structopt(long = "replay-size", default_value = "50")]
+    replay_size: usize,
+    /// Seconds to keep the messages to an sri after its last socket closed
+    #[structopt(long = "replay-retention", default_value = "60")]
+    replay_retention: u64,
+    /// Comma separated address ranges of reverse proxies, whose
+    /// X-Forwarded-For, Forwarded and X-Real-IP headers are trusted
+    #[structopt(long = "trusted-proxies", default_value = "127.0.0.0/8,::1")]
+    trusted_proxies: TrustedProxies,
     /// Hard limit for maximum number of simultaneous Websocket connections
     #[structopt(long = "max-connections", default_value = "40000")]
     max_connections: usize,
-    /// How many messages to accept, per IP, per 10s
+    /// Maximum number of simultaneous Websocket connections per client
+    /// address (or IPv6 prefix)
+    #[structopt(long = "max-connections-per-ip", default_value = "100")]
+    max_connections_per_ip: u32,
+    /// Maximum number of simultaneous Websocket connections per
+    /// authenticated user
+    #[structopt(long = "max-connections-per-user", default_value = "30")]
+    max_connections_per_user: usize,
+    /// How many credits for messages to accept, per IP, per 10s, from
+    /// anonymous sockets
     #[structopt(long = "rate-limiter-credits", default_value = "40")]
     rate_limiter_credits: u32,
+    /// How many credits for analysis messages (opening, anaDests, anaMove,
+    /// anaDrop) to accept, per IP, per 10s, from anonymous sockets
+    #[structopt(long = "rate-limiter-analysis-credits", default_value = "100")]
+    rate_limiter_analysis_credits: u32,
+    /// Like --rate-limiter-credits, but per user, for authenticated sockets
+    #[structopt(long = "rate-limiter-user-credits", default_value = "100")]
+    rate_limiter_user_credits: u32,
+    /// Like --rate-limiter-analysis-credits, but per user, for
+    /// authenticated sockets
+    #[structopt(long = "rate-limiter-user-analysis-credits", default_value = "250")]
+    rate_limiter_user_analysis_credits: u32,
+    /// Credits that messages cost, by type, like anaMove=4 (others cost 1)
+    #[structopt(long = "rate-limiter-costs", default_value = "anaMove=4,anaDrop=4,anaDests=2")]
+    rate_limiter_costs: Costs,
+    /// IPv6 clients within a prefix of this length share their rate limit
+    /// and connection limit
+    #[structopt(long = "rate-limiter-ipv6-prefix", default_value = "64")]
+    rate_limiter_ipv6_prefix: u8,
 }
 
 /// Messages we send to Websocket clients.
@@ -79,6 +218,14 @@ enum SocketIn<'a> {
     StepFailure,
     #[serde(rename = "node")]
     Node(Box<analysis::Node>),
+    #[serde(rename = "lilaStatus")]
+    LilaStatus { up: bool },
+    #[serde(rename = "askTimeout")]
+    AskTimeout { ask: &'static str },
+    #[serde(rename = "reconnect")]
+    Reconnect { delay: u64 }, // ms
+    #[serde(rename = "resync")]
+    Resync, // missed messages are no longer available
 }
 
 impl<'a> SocketIn<'a> {
@@ -138,6 +285,36 @@ enum SocketOut {
     UnexpectedMessage,
 }
 
+impl SocketOut {
+    /// Type of message, for rate limiting.
+    fn tag(&self) -> &'static str {
+        match self {
+            SocketOut::Ping { .. } => "p",
+            SocketOut::Notified => "notified",
+            SocketOut::StartWatching { .. } => "startWatching",
+            SocketOut::MoveLatency { .. } => "moveLat",
+            SocketOut::FollowingOnlines => "following_onlines",
+            SocketOut::Opening { .. } => "opening",
+            SocketOut::AnaDests { .. } => "anaDests",
+            SocketOut::AnaMove { .. } => "anaMove",
+            SocketOut::AnaDrop { .. } => "anaDrop",
+            SocketOut::EvalGet => "evalGet",
+            SocketOut::EvalPut => "evalPut",
+            SocketOut::UnexpectedMessage => "unexpected",
+        }
+    }
+
+    fn bucket(&self) -> Bucket {
+        match self {
+            SocketOut::Opening { .. } |
+            SocketOut::AnaDests { .. } |
+            SocketOut::AnaMove { .. } |
+            SocketOut::AnaDrop { .. } => Bucket::Analysis,
+            _ => Bucket::Control,
+        }
+    }
+}
+
 /// Session cookie from Play framework.
 #[derive(Debug, Deserialize)]
 struct SessionCookie {
@@ -150,27 +327,115 @@ struct SessionCookie {
 struct QueryString {
     flag: Option<Flag>,
     sri: Sri,
+    since: Option<u64>, // last seq received before reconnecting
 }
 
 /// Timeout that's used to close Websockets after some time of inactivity.
 const IDLE_TIMEOUT_TOKEN: Token = Token(1);
 const IDLE_TIMEOUT_MS: u64 = 15_000;
 
+/// Time that clients get to answer the close frame, before the connection
+/// is dropped. Uses the same timer as the idle timeout.
+const CLOSE_TIMEOUT_MS: u64 = 5_000;
+
+/// Close code for clients over their connection limit.
+const TOO_MANY_CONNECTIONS: CloseCode = CloseCode::Other(4009);
+
+/// Delays between attempts to reconnect to lila.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// Metrics in the Prometheus text format.
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn gauge(&mut self, name: &str, value: u64) {
+        self.family(name, "gauge");
+        self.sample(name, "", value);
+    }
+
+    fn counter(&mut self, name: &str, value: u64) {
+        self.family(name, "counter");
+        self.sample(name, "", value);
+    }
+
+    /// Counter with a sample for each value of `label`.
+    fn labeled_counter<L, I>(&mut self, name: &str, label: &str, samples: I)
+    where
+        L: std::fmt::Display,
+        I: IntoIterator<Item = (L, u64)>,
+    {
+        self.family(name, "counter");
+        for (value, n) in samples {
+            self.sample(name, &format!("{{{}=\"{}\"}}", label, value), n);
+        }
+    }
+
+    fn family(&mut self, name: &str, kind: &str) {
+        writeln!(self.0, "# TYPE lila_websocket_{} {}", name, kind).expect("write to string");
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: u64) {
+        writeln!(self.0, "lila_websocket_{}{} {}", name, labels, value).expect("write to string");
+    }
+}
+
 /// Shared state of this Websocket server.
+///
+/// Registries are sharded by key. Shards are locked in this order: by_id,
+/// by_user, user_sris, replays, by_sri, by_game, watched_games, flags,
+/// watching_mlat, lags, asks, by_ip. At most one shard of each registry is
+/// held at a time.
 struct App {
-    by_user: RwLock<HashMap::<UserId, Vec<Sender>>>,
-    by_game: RwLock<HashMap::<GameId, Vec<Sender>>>,
-    by_sri: RwLock<HashMap::<Sri, Vec<Sender>>>,
-    by_id: RwLock<HashMap::<SocketId, UserSocket>>,
-    watched_games: RwLock<HashMap<GameId, WatchedGame>>,
-    flags: [RwLock<HashSet<Sender>>; 2],
+    by_user: Sharded<HashMap::<UserId, Vec<Outbox>>>,
+    by_game: Sharded<HashMap::<GameId, Vec<Outbox>>>,
+    by_sri: Sharded<HashMap::<Sri, Vec<SriSocket>>>,
+    replays: Sharded<HashMap<Sri, Replay>>,
+    user_sris: Sharded<HashMap<UserId, HashSet<Sri>>>, // sris with a replay buffer
+    replay_settings: ReplaySettings,
+    replay_gaps: AtomicU64,
+    by_id: Sharded<HashMap::<SocketId, UserSocket>>,
+    watched_games: Sharded<HashMap<GameId, WatchedGame>>,
+    flags: [Sharded<HashSet<Outbox>>; 2], // sharded by sender
+    by_ip: Sharded<HashMap<IpAddr, u32>>, // number of connections
+    trusted_proxies: TrustedProxies,
+    connection_limits: ConnectionLimits,
+    rejected_ip: AtomicU64,
+    rejected_user: AtomicU64,
     lags: RwLock<HashMap::<UserId, u32>>, // buffer of user lags, to send several at once
     mlat: AtomicU32,
-    watching_mlat: RwLock<HashSet<Sender>>,
-    redis_sink: channel::Sender<String>,
+    watching_mlat: Sharded<HashSet<Outbox>>, // sharded by sender
+    lila_queue: LilaQueue,
+    ipc_version: u8, // highest supported
     sid_sink: channel::Sender<(SocketId, SessionCookie)>,
+    analysis_sinks: Vec<channel::Sender<AnalysisJob>>, // one per worker
+    analysis_rejected: AtomicU64,
     broadcaster: OnceCell<Sender>,
     connection_count: AtomicI32, // signed to allow relaxed writes with underflow
+    last_tick: Mutex<Instant>, // last mlat from lila
+    lila_down: AtomicBool,
+    lila_asks: AtomicBool, // negotiated ipc version supports asks
+    hello_retry: AtomicBool, // offer again once lila is listening
+    asks: Mutex<HashMap<u64, PendingAsk>>,
+    ask_seq: AtomicU64,
+    ask_timeout: Duration,
+    outbox_policy: OutboxPolicy,
+    deflate: Option<DeflateSettings>,
+    shutting_down: AtomicBool,
+}
+
+/// Limits for simultaneous connections of a single client.
+struct ConnectionLimits {
+    per_ip: u32,
+    per_user: usize,
+}
+
+/// Ask to lila that is waiting for a reply.
+struct PendingAsk {
+    socket_id: SocketId,
+    ask: &'static str, // client message that caused the ask
+    deadline: Instant,
 }
 
 #[derive(Debug)]
@@ -180,64 +445,341 @@ struct WatchedGame {
 }
 
 impl App {
-    fn new(redis_sink: channel::Sender<String>, sid_sink: channel::Sender<(SocketId, SessionCookie)>) -> App {
+    #[allow(clippy::too_many_arguments)]
+    fn new(lila_queue: LilaQueue, ipc_version: u8, ask_timeout: Duration, outbox_policy: OutboxPolicy, deflate: Option<DeflateSettings>, replay_settings: ReplaySettings, connection_limits: ConnectionLimits, trusted_proxies: TrustedProxies, sid_sink: channel::Sender<(SocketId, SessionCookie)>, analysis_sinks: Vec<channel::Sender<AnalysisJob>>) -> App {
         App {
-            by_user: RwLock::new(HashMap::new()),
-            by_game: RwLock::new(HashMap::new()),
-            by_sri: RwLock::new(HashMap::new()),
-            by_id: RwLock::new(HashMap::new()),
-            watched_games: RwLock::new(HashMap::new()),
-            flags: [RwLock::new(HashSet::new()), RwLock::new(HashSet::new())],
+            by_user: Sharded::new(),
+            by_game: Sharded::new(),
+            by_sri: Sharded::new(),
+            replays: Sharded::new(),
+            user_sris: Sharded::new(),
+            replay_settings,
+            replay_gaps: AtomicU64::new(0),
+            by_id: Sharded::new(),
+            watched_games: Sharded::new(),
+            flags: [Sharded::new(), Sharded::new()],
+            by_ip: Sharded::new(),
+            connection_limits,
+            trusted_proxies,
+            rejected_ip: AtomicU64::new(0),
+            rejected_user: AtomicU64::new(0),
             lags: RwLock::new(HashMap::new()),
-            redis_sink,
+            lila_queue,
+            ipc_version,
             sid_sink,
+            analysis_sinks,
+            analysis_rejected: AtomicU64::new(0),
             broadcaster: OnceCell::new(),
             connection_count: AtomicI32::new(0),
             mlat: AtomicU32::new(u32::max_value()),
-            watching_mlat: RwLock::new(HashSet::new()),
+            watching_mlat: Sharded::new(),
+            last_tick: Mutex::new(Instant::now()),
+            lila_down: AtomicBool::new(false),
+            lila_asks: AtomicBool::new(false),
+            hello_retry: AtomicBool::new(false),
+            asks: Mutex::new(HashMap::new()),
+            ask_seq: AtomicU64::new(0),
+            ask_timeout,
+            outbox_policy,
+            deflate,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    fn broadcast(&self, msg: String) {
+        if let Err(err) = self.broadcaster.get().expect("broadcaster").send(msg) {
+            log::error!("failed to broadcast: {:?}", err);
+        }
+    }
+
+    /// Tell clients if lila stopped sending ticks, probably because it is
+    /// restarting.
+    fn check_lila(&self, timeout: Duration) {
+        let since_tick = self.last_tick.lock().elapsed();
+        if since_tick > timeout && !self.lila_down.swap(true, Ordering::Relaxed) {
+            log::error!("no mlat from lila for {:?}, assuming it is down", since_tick);
+            self.broadcast(SocketIn::LilaStatus { up: false }.to_json_string());
         }
     }
 
     fn publish<'a>(&self, msg: LilaIn<'a>) {
-        self.redis_sink.send(msg.to_string()).expect("redis sink");
+        // When shutting down, lila has already been told that all sockets
+        // are gone.
+        if !self.shutting_down.load(Ordering::Relaxed) {
+            self.lila_queue.push(msg);
+        }
+    }
+
+    /// Stop accepting sockets and close existing ones spread over the given
+    /// window, so that clients do not all reconnect at the same time.
+    fn drain(&self, window: Duration, jitter: Duration) {
+        if self.shutting_down.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        self.lila_queue.push(LilaIn::DisconnectAll);
+
+        let mut senders: Vec<Outbox> = self.by_id.shards()
+            .flat_map(|shard| shard.read().values().map(|s| s.sender.clone()).collect::<Vec<_>>())
+            .collect();
+        log::warn!("shutting down, closing {} sockets over {:?}", senders.len(), window);
+
+        let mut rng = rand::thread_rng();
+        senders.shuffle(&mut rng);
+
+        const TICK: Duration = Duration::from_millis(100);
+        let ticks = max(1, window.as_millis() / TICK.as_millis()) as usize;
+        let chunk_size = max(1, senders.len().div_ceil(ticks));
+        let max_delay = jitter.as_millis() as u64;
+        for chunk in senders.chunks(chunk_size) {
+            for sender in chunk {
+                let delay = rng.gen_range(0, max_delay + 1);
+                if let Err(err) = sender.send(SocketIn::Reconnect { delay }.to_json_string()) {
+                    log::debug!("failed to send reconnect hint: {:?}", err);
+                }
+                if let Err(err) = sender.close(CloseCode::Restart) {
+                    log::debug!("failed to close socket: {:?}", err);
+                }
+            }
+            thread::sleep(TICK);
+        }
+
+        // Give the lila sink a chance to flush.
+        let flush_started = Instant::now();
+        while self.lila_queue.len() > 0 && flush_started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        thread::sleep(Duration::from_millis(500));
+        log::warn!("shut down");
+    }
+
+    /// Send a message to lila and route its reply to the given socket. Lila
+    /// versions without asks get the plain message and answer as they
+    /// always did.
+    fn ask<'a>(&self, socket_id: SocketId, ask: &'static str, msg: LilaIn<'a>) {
+        if !self.lila_asks.load(Ordering::Relaxed) {
+            return self.publish(msg);
+        }
+
+        let id = self.ask_seq.fetch_add(1, Ordering::Relaxed);
+        self.asks.lock().insert(id, PendingAsk {
+            socket_id,
+            ask,
+            deadline: Instant::now() + self.ask_timeout,
+        });
+        self.publish(LilaIn::Ask(id, &msg));
+    }
+
+    /// Tell clients about asks that lila did not answer in time.
+    fn expire_asks(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.asks.lock().retain(|_, pending| {
+            if pending.deadline <= now {
+                expired.push((pending.socket_id, pending.ask));
+                false
+            } else {
+                true
+            }
+        });
+
+        if expired.is_empty() {
+            return;
+        }
+
+        log::warn!("{} asks to lila timed out", expired.len());
+        for (socket_id, ask) in expired {
+            if let Some(user_socket) = self.by_id.read(&socket_id).get(&socket_id) {
+                if let Err(err) = user_socket.sender.send(SocketIn::AskTimeout { ask }.to_json_string()) {
+                    log::error!("failed to send ask timeout: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Send a message to all sockets of an sri, or only to those
+    /// authenticated as the user `to`, numbered and remembered in its replay
+    /// buffer.
+    fn tell_sri(&self, sri: &Sri, to: Option<&UserId>, payload: &str) {
+        let mut replays = self.replays.write(sri);
+        let payload = match replays.get_mut(sri) {
+            Some(replay) => replay.push(to, payload),
+            None => return, // no socket and nothing to resume
+        };
+
+        if let Some(entry) = self.by_sri.read(sri).get(sri) {
+            for sri_socket in entry.iter().filter(|s| to.is_none() || s.uid.as_ref() == to) {
+                if let Err(err) = sri_socket.sender.send_shared(&payload) {
+                    log::error!("failed to send to sri: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Count a connection from `client_addr`. Returns `false` if the client
+    /// already has too many connections. IPv6 clients are grouped by
+    /// `ipv6_prefix`, like for rate limiting.
+    fn accept_ip(&self, client_addr: IpAddr, ipv6_prefix: u8) -> bool {
+        let key = proxy::rate_limit_key(&client_addr, ipv6_prefix);
+        let mut by_ip = self.by_ip.write(&key);
+        let count = by_ip.get(&key).copied().unwrap_or(0);
+        if count >= self.connection_limits.per_ip {
+            self.rejected_ip.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        by_ip.insert(key, count + 1);
+        true
+    }
+
+    /// Forget an accepted connection from `client_addr`.
+    fn release_ip(&self, client_addr: IpAddr, ipv6_prefix: u8) {
+        let key = proxy::rate_limit_key(&client_addr, ipv6_prefix);
+        let mut by_ip = self.by_ip.write(&key);
+        let count = by_ip.get_mut(&key).expect("client addr in by_ip");
+        *count -= 1;
+        if *count == 0 {
+            by_ip.remove(&key);
+        }
+    }
+
+    /// Forget replay buffers of sris that did not reconnect in time.
+    fn expire_replays(&self) {
+        let mut expired = Vec::new();
+        for shard in self.replays.shards() {
+            shard.write().retain(|sri, replay| {
+                if !replay.expired(self.replay_settings.retention) {
+                    return true;
+                }
+                expired.extend(replay.uids.drain().map(|uid| (uid, sri.clone())));
+                false
+            });
+        }
+
+        for (uid, sri) in expired {
+            let mut user_sris = self.user_sris.write(&uid);
+            if let Some(sris) = user_sris.get_mut(&uid) {
+                // The sri may have been resumed in the meantime.
+                let resumed = self.replays.read(&sri).get(&sri).is_some_and(|r| r.uids.contains(&uid));
+                if !resumed {
+                    sris.remove(&sri);
+                }
+                if sris.is_empty() {
+                    user_sris.remove(&uid);
+                }
+            }
+        }
+    }
+
+    /// Offer a structured encoding. Until lila answers, we use the text
+    /// protocol, which every lila understands.
+    fn offer_hello(&self) {
+        if self.ipc_version == 0 {
+            return;
+        }
+        self.lila_queue.set_encoding(Encoding::Text);
+        self.lila_asks.store(false, Ordering::Relaxed);
+        self.hello_retry.store(true, Ordering::Relaxed);
+        self.publish(LilaIn::Hello(self.ipc_version));
+    }
+
+    /// Tell lila about all connected users and watched games again, for
+    /// example after it may have missed messages or restarted. Negotiates
+    /// the encoding again, because a restarted lila has forgotten it.
+    fn announce_state(&self) {
+        self.offer_hello();
+
+        let mut num_users = 0;
+        for shard in self.by_user.shards() {
+            let uids: Vec<UserId> = shard.read().keys().cloned().collect();
+            for uid in &uids {
+                self.lila_queue.push(LilaIn::Connect(uid));
+            }
+            num_users += uids.len();
+        }
+
+        let mut num_games = 0;
+        for shard in self.by_game.shards() {
+            let games: Vec<GameId> = shard.read().keys().cloned().collect();
+            for game in &games {
+                self.lila_queue.push(LilaIn::Watch(game));
+            }
+            num_games += games.len();
+        }
+
+        self.lila_queue.push(LilaIn::Connections(
+            max(0, self.connection_count.load(Ordering::Relaxed)) as u32
+        ));
+
+        log::info!("announced {} users and {} games", num_users, num_games);
+    }
+
+    /// Stats in the Prometheus text format.
+    fn metrics(&self) -> String {
+        let mut metrics = Metrics::default();
+        metrics.gauge("connections", max(0, self.connection_count.load(Ordering::Relaxed)) as u64);
+        metrics.gauge("lila_queue_length", self.lila_queue.len() as u64);
+
+        let overflow = self.lila_queue.total_stats();
+        metrics.labeled_counter("lila_queue_dropped_total", "msg", overflow.dropped);
+        metrics.labeled_counter("lila_queue_coalesced_total", "msg", overflow.coalesced);
+
+        metrics.labeled_counter("connections_rejected_total", "reason", vec![
+            ("ip", self.rejected_ip.load(Ordering::Relaxed)),
+            ("user", self.rejected_user.load(Ordering::Relaxed)),
+        ]);
+
+        metrics.counter("fen_conflated_total", self.outbox_policy.conflated.load(Ordering::Relaxed));
+        metrics.counter("slow_consumers_total", self.outbox_policy.slow.load(Ordering::Relaxed));
+        metrics.counter("slow_consumer_dropped_total", self.outbox_policy.dropped.load(Ordering::Relaxed));
+        metrics.counter("slow_consumer_disconnected_total", self.outbox_policy.disconnected.load(Ordering::Relaxed));
+        metrics.gauge("deflate_connections", self.deflate.as_ref().map_or(0, |deflate| deflate.connections() as u64));
+        metrics.gauge("analysis_queue_length", self.analysis_sinks.iter().map(|sink| sink.len() as u64).sum());
+        metrics.counter("analysis_rejected_total", self.analysis_rejected.load(Ordering::Relaxed));
+        metrics.gauge("replay_buffers", self.replays.shards().map(|shard| shard.read().len() as u64).sum());
+        metrics.counter("replay_gaps_total", self.replay_gaps.load(Ordering::Relaxed));
+
+        metrics.0
     }
 
     fn received(&self, msg: LilaOut) {
         match msg {
             LilaOut::TellUsers { users, payload } => {
-                let by_user = self.by_user.read();
+                let direct = Payload::from(payload);
                 for user in users {
-                    if let Some(entry) = by_user.get(&user) {
-                        for sender in entry {
-                            if let Err(err) = sender.send(Message::text(payload.to_string())) {
+                    // Sockets without sri can not resume.
+                    if let Some(entry) = self.by_user.read(&user).get(&user) {
+                        for sender in entry.iter().filter(|s| !s.is_resumable()) {
+                            if let Err(err) = sender.send_shared(&direct) {
                                 log::error!("failed to tell {}: {:?}", user, err);
                             }
                         }
                     }
+
+                    // Others through their sri, also while reconnecting.
+                    let sris = self.user_sris.read(&user).get(&user).cloned();
+                    for sri in sris.iter().flatten() {
+                        self.tell_sri(sri, Some(&user), payload);
+                    }
                 }
             }
             LilaOut::TellAll { payload } => {
-                let msg = Message::text(payload.to_string());
-                if let Err(err) = self.broadcaster.get().expect("broadcaster").send(msg) {
-                    log::error!("failed to broadcast: {:?}", err);
-                }
+                self.broadcast(payload.to_string());
             }
             LilaOut::Move { game, fen, last_uci } => {
-                self.watched_games.write().insert(game.clone(), WatchedGame {
+                self.watched_games.write(&game).insert(game.clone(), WatchedGame {
                     fen: fen.to_owned(),
                     lm: last_uci.to_owned()
                 });
 
-                let by_game = self.by_game.read();
-                if let Some(entry) = by_game.get(&game) {
-                    let msg = Message::text(SocketIn::Fen {
+                if let Some(entry) = self.by_game.read(&game).get(&game) {
+                    let payload = Payload::from(SocketIn::Fen {
                         id: &game,
                         fen,
                         lm: last_uci,
                     }.to_json_string());
 
                     for sender in entry {
-                        if let Err(err) = sender.send(msg.clone()) {
+                        if let Err(err) = sender.send_fen(&game, &payload) {
                             log::error!("failed to send fen: {:?}", err);
                         }
                     }
@@ -249,45 +791,78 @@ impl App {
                     max(0, self.connection_count.load(Ordering::Relaxed)) as u32
                 ));
                 // publish the buffered lags and clear them
-                let mut lags = self.lags.write();
+                let lags = mem::take(&mut *self.lags.write());
                 self.publish(LilaIn::Lags(&lags));
-                lags.clear();
 
                 // Update stats.
                 self.mlat.store(mlat, Ordering::Relaxed);
+                self.lila_queue.report();
+
+                // Lila is listening now, so offer again in case it missed
+                // the first offer.
+                if self.hello_retry.swap(false, Ordering::Relaxed) {
+                    self.publish(LilaIn::Hello(self.ipc_version));
+                }
+
+                // Lila is alive.
+                *self.last_tick.lock() = Instant::now();
+                if self.lila_down.swap(false, Ordering::Relaxed) {
+                    log::warn!("lila is back");
+                    self.broadcast(SocketIn::LilaStatus { up: true }.to_json_string());
+                }
 
                 // Update watching clients.
-                let msg = SocketIn::MoveLatency(mlat).to_json_string();
-                for sender in self.watching_mlat.read().iter() {
-                    if let Err(err) = sender.send(msg.clone()) {
-                        log::error!("failed to send mlat: {:?}", err);
+                let payload = Payload::from(SocketIn::MoveLatency(mlat).to_json_string());
+                for shard in self.watching_mlat.shards() {
+                    for sender in shard.read().iter() {
+                        if let Err(err) = sender.send_shared(&payload) {
+                            log::error!("failed to send mlat: {:?}", err);
+                        }
                     }
                 }
             }
             LilaOut::TellFlag { flag, payload } => {
-                let watching_flag = self.flags[flag as usize].read();
-                let msg = payload.to_string();
-                for sender in watching_flag.iter() {
-                    if let Err(err) = sender.send(msg.clone()) {
-                        log::error!("failed to send to flag ({:?}): {:?}", flag, err);
+                let payload = Payload::from(payload);
+                for shard in self.flags[flag as usize].shards() {
+                    for sender in shard.read().iter() {
+                        if let Err(err) = sender.send_shared(&payload) {
+                            log::error!("failed to send to flag ({:?}): {:?}", flag, err);
+                        }
                     }
                 }
             }
             LilaOut::TellSri { sri, payload } => {
-                if let Some(entry) = self.by_sri.read().get(&sri) {
-                    for sender in entry {
-                        if let Err(err) = sender.send(payload) {
-                            log::error!("failed to send to sri: {:?}", err);
+                self.tell_sri(&sri, None, payload);
+            }
+            LilaOut::Hello { ref node, .. } | LilaOut::Reply { ref node, .. } if node.as_ref() != self.lila_queue.node() => {
+                // Meant for another node.
+            }
+            LilaOut::Hello { version, .. } => {
+                let encoding = Encoding::from_version(min(version, self.ipc_version));
+                log::info!("lila accepted ipc version {} ({:?})", version, encoding);
+                self.lila_queue.set_encoding(encoding);
+                self.lila_asks.store(min(version, self.ipc_version) >= 2, Ordering::Relaxed);
+                self.hello_retry.store(false, Ordering::Relaxed);
+            }
+            LilaOut::Reply { id, payload, .. } => {
+                let pending = self.asks.lock().remove(&id);
+                match pending {
+                    Some(pending) => {
+                        if let Some(user_socket) = self.by_id.read(&pending.socket_id).get(&pending.socket_id) {
+                            if let Err(err) = user_socket.sender.send(payload) {
+                                log::error!("failed to send reply: {:?}", err);
+                            }
                         }
                     }
+                    None => log::warn!("reply to unknown or expired ask {}", id),
                 }
             }
+            LilaOut::Resync => {
+                log::warn!("lila requested resync");
+                self.announce_state();
+            }
             LilaOut::DisconnectUser { uid } => {
-                let senders = {
-                    let by_user = self.by_user.read();
-                    let senders = by_user.get(&uid);
-                    senders.cloned()
-                };
+                let senders = self.by_user.read(&uid).get(&uid).cloned();
                 if let Some(senders) = senders {
                     for sender in senders {
                         if let Err(err) = sender.close(CloseCode::Normal) {
@@ -300,20 +875,69 @@ impl App {
     }
 }
 
+/// Analysis request, computed by a worker thread.
+enum AnalysisRequest {
+    Dests(analysis::GetDests),
+    Step(analysis::PlayStep),
+}
+
+struct AnalysisJob {
+    outbox: Outbox,
+    req: AnalysisRequest,
+    msg: String, // for logging
+}
+
+impl AnalysisJob {
+    fn respond(self) {
+        let res = match self.req {
+            AnalysisRequest::Dests(d) => match d.respond() {
+                Ok(res) => SocketIn::Dests(res),
+                Err(err) => {
+                    log::warn!("analysis dests failure {:?}: {}", err, self.msg);
+                    SocketIn::DestsFailure
+                }
+            },
+            AnalysisRequest::Step(d) => match d.respond() {
+                Ok(res) => SocketIn::Node(Box::new(res)),
+                Err(err) => {
+                    log::warn!("analysis step failure {:?}: {}", err, self.msg);
+                    SocketIn::StepFailure
+                }
+            },
+        };
+
+        if let Err(err) = self.outbox.send(res.to_json_string()) {
+            log::error!("failed to send analysis: {:?}", err);
+        }
+    }
+
+    fn failure(&self) -> SocketIn<'static> {
+        match self.req {
+            AnalysisRequest::Dests(_) => SocketIn::DestsFailure,
+            AnalysisRequest::Step(_) => SocketIn::StepFailure,
+        }
+    }
+}
+
 /// A Websocket client connection.
 struct Socket {
     app: &'static App,
     socket_id: SocketId,
-    rate_limiter: KeyedRateLimiter<IpAddr>,
+    rate_limiter: RateLimiter,
+    ipv6_prefix: u8, // for rate limiting
     client_addr: Option<IpAddr>,
     user_agent: Option<String>,
     rate_limited_once: bool,
+    rejected: bool, // over connection limit, closing
+    closing: bool, // close frame sent after a timeout or rejection
     sender: Sender,
+    outbox: Outbox,
     watching: HashSet<GameId>,
     flag: Option<Flag>,
     sri: Option<Sri>,
     idle_timeout: Option<Timeout>,
-    log_ignore: bool // stop logging errors from this client
+    log_ignore: bool, // stop logging errors from this client
+    deflate: Option<Deflate>, // if negotiated
 }
 
 /// Uniquely identifies a socket connection over the entire runtime of the
@@ -329,26 +953,75 @@ enum SocketAuth {
 
 struct UserSocket {
     app: &'static App,
-    sender: Sender,
+    sender: Outbox,
+    sri: Option<Sri>,
+    join_since: Option<u64>, // replay from here and join by_sri, once authenticated
     auth: SocketAuth,
     pending_notified: bool,
     pending_following_onlines: bool,
 }
 
+/// Socket that gets the messages to its sri.
+struct SriSocket {
+    sender: Outbox,
+    uid: Option<UserId>, // also gets the messages to this user
+}
+
+/// Message to lila about a user socket. Collected while by_id is locked and
+/// published after releasing it, because registry locks are never held
+/// while publishing.
+enum UserEvent {
+    Connect(UserId),
+    Disconnect(UserId),
+    Notified(UserId),
+    FollowingOnlines(UserId),
+}
+
+type UserEvents = SmallVec<[UserEvent; 2]>;
+
+impl App {
+    fn publish_user_events<I: IntoIterator<Item = UserEvent>>(&self, socket_id: SocketId, events: I) {
+        for event in events {
+            match event {
+                UserEvent::Connect(uid) => self.publish(LilaIn::Connect(&uid)),
+                UserEvent::Disconnect(uid) => self.publish(LilaIn::Disconnect(&uid)),
+                UserEvent::Notified(uid) => self.publish(LilaIn::Notified(&uid)),
+                UserEvent::FollowingOnlines(uid) => self.ask(socket_id, "following_onlines", LilaIn::Friends(&uid)),
+            }
+        }
+    }
+}
+
 impl UserSocket {
-    fn set_user(&mut self, maybe_uid: Option<UserId>) {
+    #[must_use]
+    fn set_user(&mut self, maybe_uid: Option<UserId>) -> UserEvents {
+        let mut events = UserEvents::new();
+
         // Connected.
         let auth = match maybe_uid {
+            Some(uid) if self.app.by_user.read(&uid).get(&uid).is_some_and(|v| v.len() >= self.app.connection_limits.per_user) => {
+                // Too many connections. Only the session lookup thread
+                // adds users, so the count can not grow in the meantime.
+                self.app.rejected_user.fetch_add(1, Ordering::Relaxed);
+                if let Err(err) = self.sender.close_with_reason(TOO_MANY_CONNECTIONS, "too many connections") {
+                    log::error!("failed to close socket over user limit: {:?}", err);
+                }
+                SocketAuth::Anonymous
+            },
             Some(uid) => {
-                self.app.by_user.write()
+                self.app.by_user.write(&uid)
                     .entry(uid.clone())
                     .and_modify(|v| v.push(self.sender.clone()))
                     .or_insert_with(|| {
                         log::debug!("first open: {}", uid);
-                        self.app.publish(LilaIn::Connect(&uid));
+                        events.push(UserEvent::Connect(uid.clone()));
                         vec![self.sender.clone()]
                     });
 
+                if let Some(ref sri) = self.sri {
+                    self.resume_user_messages(&uid, sri);
+                }
+
                 SocketAuth::Authenticated(uid)
             },
             None => SocketAuth::Anonymous,
@@ -357,7 +1030,7 @@ impl UserSocket {
         match mem::replace(&mut self.auth, auth) {
             // Disconnected.
             SocketAuth::Authenticated(uid) => {
-                let mut by_user = self.app.by_user.write();
+                let mut by_user = self.app.by_user.write(&uid);
                 let entry = by_user.get_mut(&uid).expect("uid in by_user");
                 let idx = entry.iter().position(|s| s.token() == self.sender.token()).expect("sender in by_user entry");
                 entry.swap_remove(idx);
@@ -366,21 +1039,93 @@ impl UserSocket {
                 if entry.is_empty() {
                     by_user.remove(&uid);
                     log::debug!("last close: {}", uid);
-                    self.app.publish(LilaIn::Disconnect(&uid));
+                    events.push(UserEvent::Disconnect(uid));
                 }
             },
             // Authentication request finished.
             SocketAuth::Requested => {
                 if self.pending_notified {
-                    self.on_notified();
+                    events.extend(self.on_notified());
                 }
 
                 if self.pending_following_onlines {
-                    self.on_following_onlines();
+                    events.extend(self.on_following_onlines());
                 }
             },
             SocketAuth::Anonymous => (),
         }
+
+        self.join_sri();
+        events
+    }
+
+    /// Start getting the messages to the sri, after replaying those that
+    /// the client missed. Done once authentication finished, so that
+    /// messages to the user are never sent or replayed to anyone else.
+    fn join_sri(&mut self) {
+        let since = match self.join_since.take() {
+            Some(since) => since,
+            None => return,
+        };
+        let sri = self.sri.as_ref().expect("sri to join");
+        let uid = self.user_id().cloned();
+
+        // Hold the replay buffer while joining, so that no message falls in
+        // between.
+        let replays = self.app.replays.read(sri);
+        match replays.get(sri).expect("sri in replays").since(since, uid.as_ref()) {
+            Some(missed) => {
+                for payload in missed {
+                    if let Err(err) = self.sender.send_shared(payload) {
+                        log::error!("failed to resume: {:?}", err);
+                    }
+                }
+            }
+            None => {
+                self.app.replay_gaps.fetch_add(1, Ordering::Relaxed);
+                if let Err(err) = self.sender.send(SocketIn::Resync.to_json_string()) {
+                    log::error!("failed to send resync: {:?}", err);
+                }
+            }
+        }
+
+        self.app.by_sri.write(sri)
+            .entry(sri.clone())
+            .or_default()
+            .push(SriSocket { sender: self.sender.clone(), uid });
+    }
+
+    /// Stop getting the messages to the sri, and detach from the replay
+    /// buffer, which is kept for a while in case the client reconnects.
+    fn leave_sri(&mut self) {
+        let sri = match self.sri.take() {
+            Some(sri) => sri,
+            None => return,
+        };
+
+        self.app.replays.write(&sri).get_mut(&sri).expect("sri in replays").detach();
+
+        if self.join_since.take().is_none() {
+            let mut by_sri = self.app.by_sri.write(&sri);
+            let senders = by_sri.get_mut(&sri).expect("sri in by_sri");
+            let idx = senders.iter().position(|s| s.sender.token() == self.sender.token()).expect("sender in senders");
+            senders.swap_remove(idx);
+            if senders.is_empty() {
+                by_sri.remove(&sri);
+            }
+        }
+    }
+
+    /// Deliver messages to the user through the replay buffer of the sri.
+    /// Other users of the sri keep getting theirs, because anyone can
+    /// present any sri.
+    fn resume_user_messages(&self, uid: &UserId, sri: &Sri) {
+        match self.app.replays.write(sri).get_mut(sri) {
+            Some(replay) => replay.uids.insert(uid.clone()),
+            None => return,
+        };
+
+        self.app.user_sris.write(uid).entry(uid.clone()).or_default().insert(sri.clone());
     }
 
     fn on_ping(&self, lag: u32) {
@@ -389,22 +1134,26 @@ impl UserSocket {
         }
     }
 
-    fn on_notified(&mut self) {
+    #[must_use]
+    fn on_notified(&mut self) -> Option<UserEvent> {
         self.pending_notified = false;
         match &self.auth {
             SocketAuth::Requested => self.pending_notified = true,
-            SocketAuth::Authenticated(uid) => self.app.publish(LilaIn::Notified(uid)),
+            SocketAuth::Authenticated(uid) => return Some(UserEvent::Notified(uid.clone())),
             SocketAuth::Anonymous => log::warn!("anon notified"),
         }
+        None
     }
 
-    fn on_following_onlines(&mut self) {
+    #[must_use]
+    fn on_following_onlines(&mut self) -> Option<UserEvent> {
         self.pending_following_onlines = false;
         match &self.auth {
             SocketAuth::Requested => self.pending_following_onlines = true,
-            SocketAuth::Authenticated(uid) => self.app.publish(LilaIn::Friends(uid)),
+            SocketAuth::Authenticated(uid) => return Some(UserEvent::FollowingOnlines(uid.clone())),
             SocketAuth::Anonymous => log::debug!("anon following_onlines"),
         }
+        None
     }
 
     fn user_id(&self) -> Option<&UserId> {
@@ -415,19 +1164,57 @@ impl UserSocket {
     }
 }
 
+impl Socket {
+    /// Queue analysis for the worker threads, or fail immediately if they
+    /// are too busy. All requests of a socket go to the same worker, so
+    /// that the replies arrive in order.
+    #[allow(clippy::result_large_err)] // ws::Result
+    fn analyse(&self, req: AnalysisRequest, msg: &str) -> ws::Result<()> {
+        let job = AnalysisJob {
+            outbox: self.outbox.clone(),
+            req,
+            msg: msg.to_owned(),
+        };
+
+        let sinks = &self.app.analysis_sinks;
+        match sinks[(self.socket_id.0 % sinks.len() as u64) as usize].try_send(job) {
+            Ok(()) => Ok(()),
+            Err(channel::TrySendError::Full(job)) => {
+                self.app.analysis_rejected.fetch_add(1, Ordering::Relaxed);
+                self.outbox.send(job.failure().to_json_string())
+            }
+            Err(channel::TrySendError::Disconnected(_)) => panic!("analysis workers gone"),
+        }
+    }
+}
+
 impl Handler for Socket {
     fn on_open(&mut self, handshake: Handshake) -> ws::Result<()> {
         // Update connection count.
         self.app.connection_count.fetch_add(1, Ordering::Relaxed);
 
         // Get client address.
-        self.client_addr = handshake.request.client_addr()?.and_then(|ip| ip.parse().ok());
+        let header = |name: &str| handshake.request.header(name).and_then(|h| str::from_utf8(h).ok());
+        self.client_addr = handshake.peer_addr.and_then(|peer| {
+            self.app.trusted_proxies.client_addr(peer.ip(), header("x-forwarded-for"), header("forwarded"), header("x-real-ip"))
+        });
 
         // Get user agent.
         self.user_agent = handshake.request.header("user-agent")
             .and_then(|h| str::from_utf8(h).ok())
             .map(|h| h.to_owned());
 
+        // Limit connections per client address. Do not wait forever for
+        // rejected clients to answer the close frame.
+        if let Some(client_addr) = self.client_addr {
+            if !self.app.accept_ip(client_addr, self.ipv6_prefix) {
+                self.rejected = true;
+                self.closing = true;
+                self.sender.close_with_reason(TOO_MANY_CONNECTIONS, "too many connections")?;
+                return self.sender.timeout(CLOSE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN);
+            }
+        }
+
         // Parse session cookie.
         let maybe_cookie = handshake.request.header("cookie")
             .and_then(|h| str::from_utf8(h).ok())
@@ -443,37 +1230,28 @@ impl Handler for Socket {
                 serde_urlencoded::from_str::<SessionCookie>(&s[idx..]).ok()
             });
 
-        // Update by_id.
-        self.app.by_id.write().insert(self.socket_id, UserSocket {
-            app: self.app,
-            auth: if maybe_cookie.is_some() { SocketAuth::Requested } else { SocketAuth::Anonymous },
-            pending_notified: false,
-            pending_following_onlines: false,
-            sender: self.sender.clone(),
-        });
-
-        // Request authentication.
-        if let Some(cookie) = maybe_cookie {
-            self.app.sid_sink.send((self.socket_id, cookie)).expect("auth request");
-        }
-
         // Parse query string.
+        let mut join_since = None;
         let mut uri = handshake.request.resource().splitn(2, '?');
         if let (_, Some(query_string)) = (uri.next().unwrap(), uri.next()) {
             match serde_urlencoded::from_str::<QueryString>(query_string) {
-                Ok(QueryString { flag, sri }) => {
+                Ok(QueryString { flag, sri, since }) => {
                     // Subscribe to flag.
                     self.flag = flag;
                     if let Some(flag) = flag {
-                        self.app.flags[flag as usize].write().insert(self.sender.clone());
+                        self.app.flags[flag as usize].write(&self.outbox).insert(self.outbox.clone());
                     }
 
-                    // Add sri.
-                    self.sri = Some(sri.clone());
-                    self.app.by_sri.write()
-                        .entry(sri)
-                        .and_modify(|v| v.push(self.sender.clone()))
-                        .or_insert_with(|| vec![self.sender.clone()]);
+                    // Attach to the replay buffer. Messages to the sri are
+                    // only sent after authentication, starting where the
+                    // client left off, or from now on.
+                    let mut replays = self.app.replays.write(&sri);
+                    let replay = replays.entry(sri.clone())
+                        .or_insert_with(|| Replay::new(self.app.replay_settings.size));
+                    replay.attach();
+                    join_since = Some(since.unwrap_or_else(|| replay.last_seq()));
+                    self.outbox.set_resumable();
+                    self.sri = Some(sri);
                 },
                 Err(err) => {
                     log::warn!("invalid query string ({:?}): {}", err, query_string);
@@ -481,10 +1259,72 @@ impl Handler for Socket {
             }
         }
 
+        // Update by_id. Anonymous sockets can join the sri right away.
+        let mut user_socket = UserSocket {
+            app: self.app,
+            auth: if maybe_cookie.is_some() { SocketAuth::Requested } else { SocketAuth::Anonymous },
+            pending_notified: false,
+            pending_following_onlines: false,
+            sender: self.outbox.clone(),
+            sri: self.sri.clone(),
+            join_since,
+        };
+        if maybe_cookie.is_none() {
+            user_socket.join_sri();
+        }
+        self.app.by_id.write(&self.socket_id).insert(self.socket_id, user_socket);
+
+        // Request authentication.
+        if let Some(cookie) = maybe_cookie {
+            self.app.sid_sink.send((self.socket_id, cookie)).expect("auth request");
+        }
+
+        // Tell new clients if lila is currently unavailable.
+        if self.app.lila_down.load(Ordering::Relaxed) {
+            self.outbox.send(SocketIn::LilaStatus { up: false }.to_json_string())?;
+        }
+
         // Start idle timeout.
         self.sender.timeout(IDLE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)
     }
 
+    fn on_request(&mut self, req: &Request) -> ws::Result<Response> {
+        // Reject new sockets when shutting down. nginx tries the other
+        // instance (proxy_next_upstream http_503).
+        if self.app.shutting_down.load(Ordering::Relaxed) {
+            return Ok(Response::new(503, "Service Unavailable", b"shutting down".to_vec()));
+        }
+
+        let mut res = Response::from_request(req)?;
+
+        // Negotiate compression.
+        if let Some(ref settings) = self.app.deflate {
+            if let Some((deflate, ext)) = deflate::negotiate(&req.extensions()?, settings) {
+                res.add_extension(&ext);
+                self.deflate = Some(deflate);
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
+        match self.deflate {
+            Some(ref mut deflate) => deflate.on_frame(frame),
+            None if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() => {
+                Err(ws::Error::new(ws::ErrorKind::Protocol, "unexpected reserved bits"))
+            }
+            None => Ok(Some(frame)),
+        }
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
+        Ok(Some(match self.deflate {
+            Some(ref mut deflate) => deflate.on_send_frame(frame),
+            None => frame,
+        }))
+    }
+
     fn on_close(&mut self, _: CloseCode, _: &str) {
         // Update connection count. (Due to relaxed ordering this can
         // temporarily be less than 0).
@@ -497,32 +1337,40 @@ impl Handler for Socket {
             }
         }
 
-        // Update by_sri.
-        if let Some(sri) = self.sri.take() {
-            let mut by_sri = self.app.by_sri.write();
-            let senders = by_sri.get_mut(&sri).expect("sri in by_sri");
-            let our_token = self.sender.token();
-            let idx = senders.iter().position(|s| s.token() == our_token).expect("sender in senders");
-            senders.swap_remove(idx);
-            if senders.is_empty() {
-                by_sri.remove(&sri);
-            }
+        // Nothing else to clean up after rejecting.
+        if self.rejected {
+            return;
+        }
+
+        // Update by_ip.
+        if let Some(client_addr) = self.client_addr {
+            self.app.release_ip(client_addr, self.ipv6_prefix);
         }
 
-        // Update by_id.
-        let mut user_socket = self.app.by_id.write().remove(&self.socket_id).expect("user socket");
-        user_socket.set_user(None);
+        // Update by_id and by_sri. Leaving the sri after removing the user
+        // socket, so that the session lookup can not join it anymore.
+        let mut user_socket = self.app.by_id.write(&self.socket_id).remove(&self.socket_id).expect("user socket");
+        user_socket.leave_sri();
+        let events = user_socket.set_user(None);
+        self.app.publish_user_events(self.socket_id, events);
 
         // Update by_game.
-        let mut by_game = self.app.by_game.write();
         let our_token = self.sender.token();
         for game in self.watching.drain() {
-            let watchers = by_game.get_mut(&game).expect("game in by_game");
-            let idx = watchers.iter().position(|s| s.token() == our_token).expect("sender in watchers");
-            watchers.swap_remove(idx);
-            if watchers.is_empty() {
-                by_game.remove(&game);
-                self.app.watched_games.write().remove(&game);
+            let unwatched = {
+                let mut by_game = self.app.by_game.write(&game);
+                let watchers = by_game.get_mut(&game).expect("game in by_game");
+                let idx = watchers.iter().position(|s| s.token() == our_token).expect("sender in watchers");
+                watchers.swap_remove(idx);
+                if watchers.is_empty() {
+                    by_game.remove(&game);
+                    self.app.watched_games.write(&game).remove(&game);
+                    true
+                } else {
+                    false
+                }
+            };
+            if unwatched {
                 log::debug!("no more watchers for {:?}", game);
                 self.app.publish(LilaIn::Unwatch(&game));
             }
@@ -530,29 +1378,17 @@ impl Handler for Socket {
 
         // Unsubscribe from flag.
         if let Some(flag) = self.flag.take() {
-            self.app.flags[flag as usize].write().remove(&self.sender);
+            self.app.flags[flag as usize].write(&self.outbox).remove(&self.outbox);
         }
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        if let Some(client_addr) = self.client_addr {
-            if self.rate_limiter.check(client_addr).is_err() {
-                if !mem::replace(&mut self.rate_limited_once, true) {
-                    log::warn!("socket of client {} rate limited (will log only once)", client_addr);
-                }
-                return Ok(()); // ignore message
-            }
-        }
-
-        self.sender.timeout(IDLE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)?;
-
-        // Fast path for ping.
-        let msg = msg.as_text()?;
-        if msg == "null" {
-            return self.sender.send(Message::text("0"));
+        if self.rejected {
+            return Ok(()); // closing
         }
 
         // Limit message size.
+        let msg = msg.as_text()?;
         if msg.len() > 2048 {
             log::warn!("very long message ({} bytes): {}", msg.len(), msg);
             return self.sender.close(CloseCode::Size);
@@ -560,29 +1396,67 @@ impl Handler for Socket {
             log::info!("long message ({} bytes): {}", msg.len(), msg);
         }
 
-        match serde_json::from_str(msg) {
+        // Fast path for ping.
+        let parsed: Option<Result<SocketOut, _>> = if msg == "null" { None } else { Some(serde_json::from_str(msg)) };
+
+        // Rate limit, depending on the type of message. Authenticated users
+        // are limited by user id, others by address.
+        let (bucket, tag) = match parsed {
+            Some(Ok(ref parsed)) => (parsed.bucket(), parsed.tag()),
+            Some(Err(_)) => (Bucket::Control, "invalid"),
+            None => (Bucket::Control, "p"),
+        };
+        let uid = self.app.by_id.read(&self.socket_id).get(&self.socket_id).expect("user socket").user_id().cloned();
+        let client = match (uid.as_ref(), self.client_addr) {
+            (Some(uid), _) => Some(Client::User(uid)),
+            (None, Some(client_addr)) => Some(Client::Anonymous(proxy::rate_limit_key(&client_addr, self.ipv6_prefix))),
+            (None, None) => None,
+        };
+        if let Some(client) = client {
+            if !self.rate_limiter.check(client, bucket, tag) {
+                if !mem::replace(&mut self.rate_limited_once, true) {
+                    match (uid, self.client_addr) {
+                        (Some(uid), _) => log::warn!("socket of user {} rate limited (will log only once)", uid),
+                        (None, Some(client_addr)) => log::warn!("socket of client {} rate limited (will log only once)", client_addr),
+                        (None, None) => (),
+                    }
+                }
+                return Ok(()); // ignore message
+            }
+        }
+
+        self.sender.timeout(IDLE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)?;
+
+        let parsed = match parsed {
+            Some(parsed) => parsed,
+            None => return self.outbox.pong(),
+        };
+
+        match parsed {
             Ok(SocketOut::Ping { l }) => {
                 if let Some(lag) = l {
                     if let Ok(lag) = lag.try_into() {
-                        self.app.by_id.read().get(&self.socket_id).expect("user socket").on_ping(lag);
+                        self.app.by_id.read(&self.socket_id).get(&self.socket_id).expect("user socket").on_ping(lag);
                     } else {
                         log::warn!("negative lag: {}, user-agent: {:?}", lag, self.user_agent);
                     }
                 }
-                self.sender.send(Message::text("0"))
+                self.outbox.pong()
             }
             Ok(SocketOut::Notified) => {
-                let mut write_guard = self.app.by_id.write();
-                write_guard.get_mut(&self.socket_id)
+                let event = self.app.by_id.write(&self.socket_id)
+                    .get_mut(&self.socket_id)
                     .expect("user socket")
                     .on_notified();
+                self.app.publish_user_events(self.socket_id, event);
                 Ok(())
             }
             Ok(SocketOut::FollowingOnlines) => {
-                let mut write_guard = self.app.by_id.write();
-                write_guard.get_mut(&self.socket_id)
+                let event = self.app.by_id.write(&self.socket_id)
+                    .get_mut(&self.socket_id)
                     .expect("user socket")
                     .on_following_onlines();
+                self.app.publish_user_events(self.socket_id, event);
                 Ok(())
             }
             Ok(SocketOut::StartWatching { d }) => {
@@ -590,8 +1464,8 @@ impl Handler for Socket {
                     if self.watching.insert(game.clone()) {
 
                         // If cached, send current game state immediately.
-                        if let Some(state) = self.app.watched_games.read().get(&game) {
-                            self.sender.send(SocketIn::Fen {
+                        if let Some(state) = self.app.watched_games.read(&game).get(&game) {
+                            self.outbox.send(SocketIn::Fen {
                                 id: &game,
                                 fen: &state.fen,
                                 lm: &state.lm,
@@ -599,17 +1473,21 @@ impl Handler for Socket {
                         }
 
                         // Subscribe to updates.
-                        self.app.by_game.write()
+                        let mut first = false;
+                        self.app.by_game.write(&game)
                             .entry(game.clone())
                             .and_modify(|v| {
-                                v.push(self.sender.clone());
+                                v.push(self.outbox.clone());
                                 log::debug!("also watching {:?} ({} watchers)", game, v.len());
                             })
                             .or_insert_with(|| {
-                                log::debug!("start watching: {:?}", game);
-                                self.app.publish(LilaIn::Watch(&game));
-                                vec![self.sender.clone()]
+                                first = true;
+                                vec![self.outbox.clone()]
                             });
+                        if first {
+                            log::debug!("start watching: {:?}", game);
+                            self.app.publish(LilaIn::Watch(&game));
+                        }
                     }
                 }
                 if self.watching.len() > 20 {
@@ -618,56 +1496,37 @@ impl Handler for Socket {
                 Ok(())
             },
             Ok(SocketOut::MoveLatency { d }) => {
-                let mut watching_mlat = self.app.watching_mlat.write();
+                let mut watching_mlat = self.app.watching_mlat.write(&self.outbox);
                 if d {
-                    if watching_mlat.insert(self.sender.clone()) {
-                        self.sender.send(SocketIn::MoveLatency(
+                    if watching_mlat.insert(self.outbox.clone()) {
+                        self.outbox.send(SocketIn::MoveLatency(
                             self.app.mlat.load(Ordering::Relaxed)
                         ).to_json_string())?;
                     }
                 } else {
-                    watching_mlat.remove(&self.sender);
+                    watching_mlat.remove(&self.outbox);
                 }
                 Ok(())
             },
             Ok(SocketOut::Opening { d }) => {
                 if let Some(response) = d.respond() {
-                    self.sender.send(SocketIn::Opening(response).to_json_string())?;
+                    self.outbox.send(SocketIn::Opening(response).to_json_string())?;
                 }
                 Ok(())
             }
             Ok(SocketOut::AnaDests { d }) => {
-                self.sender.send(match d.respond() {
-                    Ok(res) => SocketIn::Dests(res),
-                    Err(err) => {
-                        log::warn!("analysis dests failure {:?}: {}", err, msg);
-                        SocketIn::DestsFailure
-                    },
-                }.to_json_string())
+                self.analyse(AnalysisRequest::Dests(d), msg)
             }
             Ok(SocketOut::AnaMove { d }) => {
-                self.sender.send(match analysis::PlayStep::from(d).respond() {
-                    Ok(res) => SocketIn::Node(Box::new(res)),
-                    Err(err) => {
-                        log::warn!("analysis step failure {:?}: {}", err, msg);
-                        SocketIn::StepFailure
-                    }
-                }.to_json_string())
+                self.analyse(AnalysisRequest::Step(d.into()), msg)
             }
             Ok(SocketOut::AnaDrop { d }) => {
-                self.sender.send(match analysis::PlayStep::from(d).respond() {
-                    Ok(res) => SocketIn::Node(Box::new(res)),
-                    Err(err) => {
-                        log::warn!("analysis step failure {:?}: {}", err, msg);
-                        SocketIn::StepFailure
-                    }
-                }.to_json_string())
+                self.analyse(AnalysisRequest::Step(d.into()), msg)
             }
             Ok(SocketOut::EvalGet) | Ok(SocketOut::EvalPut) => {
                 if let Some(ref sri) = self.sri {
-                    let by_id = self.app.by_id.read();
-                    let uid = by_id.get(&self.socket_id).expect("user socket").user_id();
-                    self.app.publish(LilaIn::TellSri(sri, uid, msg));
+                    let uid = self.app.by_id.read(&self.socket_id).get(&self.socket_id).expect("user socket").user_id().cloned();
+                    self.app.publish(LilaIn::TellSri(sri, uid.as_ref(), msg));
                 } else {
                     log::warn!("sri required for: {}", msg);
                 }
@@ -697,8 +1556,25 @@ impl Handler for Socket {
 
     fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
         assert_eq!(event, IDLE_TIMEOUT_TOKEN);
+        if self.closing {
+            // Drop the connection.
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer to close frame").into());
+        }
+
         log::debug!("closing socket due to timeout");
-        self.sender.close(CloseCode::Away)
+        self.closing = true;
+        self.sender.close(CloseCode::Away)?;
+        self.sender.timeout(CLOSE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        match err.kind {
+            ws::ErrorKind::Io(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                log::debug!("dropping socket: {}", err);
+            }
+            ws::ErrorKind::Io(ref err) if err.kind() == io::ErrorKind::ConnectionReset => (),
+            _ => log::error!("socket error: {:?}", err),
+        }
     }
 }
 
@@ -707,32 +1583,133 @@ fn main() {
 
     crossbeam::scope(|s| {
         let opt = Opt::from_args();
+        assert!((9..=15).contains(&opt.deflate_window_bits), "--deflate-window-bits must be between 9 and 15");
+        assert!(opt.rate_limiter_ipv6_prefix <= 128, "--rate-limiter-ipv6-prefix must be at most 128");
 
-        let (redis_sink, redis_recv) = channel::unbounded();
+        let lila_queue = LilaQueue::new(opt.lila_queue_size, opt.lila_queue_overflow, opt.node.clone());
         let (sid_sink, sid_recv) = channel::unbounded();
-        let app: &'static App = Box::leak(Box::new(App::new(redis_sink, sid_sink)));
+        assert!(opt.analysis_threads > 0, "--analysis-threads must be at least 1");
+        let (analysis_sinks, analysis_recvs): (Vec<_>, Vec<_>) = (0..opt.analysis_threads)
+            .map(|_| channel::bounded::<AnalysisJob>(opt.analysis_queue_size.div_ceil(opt.analysis_threads)))
+            .unzip();
+        let app: &'static App = Box::leak(Box::new(App::new(
+            lila_queue,
+            opt.ipc_version,
+            Duration::from_secs(opt.ask_timeout),
+            OutboxPolicy::new(opt.max_backlog, Duration::from_secs(opt.slow_consumer_grace), opt.conflate_backlog),
+            if opt.no_deflate { None } else {
+                Some(DeflateSettings::new(opt.deflate_window_bits, opt.deflate_min_size, opt.max_deflate_connections))
+            },
+            ReplaySettings {
+                size: opt.replay_size,
+                retention: Duration::from_secs(opt.replay_retention),
+            },
+            ConnectionLimits {
+                per_ip: opt.max_connections_per_ip,
+                per_user: opt.max_connections_per_user,
+            },
+            opt.trusted_proxies.clone(),
+            sid_sink,
+            analysis_sinks)));
 
-        let rate_limiter = KeyedRateLimiter::<IpAddr>::new(
-            NonZeroU32::new(opt.rate_limiter_credits).expect("non-zero credits"),
-            Duration::from_secs(10));
+        let ip_credits = Credits {
+            control: NonZeroU32::new(opt.rate_limiter_credits).expect("non-zero credits"),
+            analysis: NonZeroU32::new(opt.rate_limiter_analysis_credits).expect("non-zero analysis credits"),
+        };
+        let user_credits = Credits {
+            control: NonZeroU32::new(opt.rate_limiter_user_credits).expect("non-zero user credits"),
+            analysis: NonZeroU32::new(opt.rate_limiter_user_analysis_credits).expect("non-zero user analysis credits"),
+        };
+        assert!(opt.rate_limiter_costs.max() <= min(ip_credits.min(), user_credits.min()), "--rate-limiter-costs must not exceed credits");
+        let rate_limiter = RateLimiter::new(ip_credits, user_credits, opt.rate_limiter_costs.clone());
 
-        // Clear connections and subscriptions from previous process.
+        // Clear connections and subscriptions from previous process (on
+        // this node).
         app.publish(LilaIn::DisconnectAll);
 
+        // Offer a structured encoding.
+        app.offer_hello();
+
+        // Connections to lila.
+        let transport: Box<dyn Transport> = match opt.transport {
+            TransportKind::Redis => Box::new(RedisTransport::new(&opt.redis)),
+            TransportKind::RedisStreams => {
+                let group = match opt.node {
+                    Some(ref node) => format!("{}-{}", opt.stream_group, node),
+                    None => opt.stream_group.clone(),
+                };
+                Box::new(RedisStreamTransport::new(&opt.redis, &group))
+            }
+            TransportKind::Unix => Box::new(UnixTransport::new(opt.unix_socket.clone())),
+            TransportKind::Memory => {
+                let (transport, lila) = MemoryTransport::new();
+                let MemoryLila { incoming, outgoing } = lila;
+
+                // Stand-in for lila: log messages it would receive, and read
+                // messages it would send from stdin.
+                s.builder().name("memory lila".to_owned()).spawn(move |_| {
+                    for msg in incoming {
+                        log::info!("site-in: {}", msg);
+                    }
+                }).unwrap();
+                s.builder().name("memory lila stdin".to_owned()).spawn(move |_| {
+                    for line in io::stdin().lock().lines() {
+                        outgoing.send(line.expect("read stdin")).expect("memory lila");
+                    }
+                }).unwrap();
+
+                Box::new(transport)
+            }
+        };
+        let transport: &'static dyn Transport = Box::leak(transport);
+
         // Thread for outgoing messages to lila.
         let opt_inner = opt.clone();
-        s.builder().name("redis sink".to_owned()).spawn(move |_| {
-            let mut redis = redis::Client::open(opt_inner.redis.as_str())
-                .expect("redis open for publish")
-                .get_connection()
-                .expect("redis connection for publish");
+        s.builder().name("lila sink".to_owned()).spawn(move |_| {
+            let mut backoff = Backoff::new(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX);
+            let mut batch: Vec<String> = Vec::new(); // not yet sent
+            let batch_size = max(1, opt_inner.batch_size);
 
             loop {
-                let msg: String = redis_recv.recv().expect("redis recv");
-                log::trace!("site-in: {}", msg);
-                let ret: u32 = redis.publish("site-in", msg).expect("publish site-in");
-                if ret == 0 {
-                    log::error!("lila missed a message");
+                let mut sink = match transport.sink() {
+                    Ok(sink) => sink,
+                    Err(err) => {
+                        log::error!("connection to site-in failed: {}", err);
+                        backoff.wait();
+                        continue;
+                    }
+                };
+
+                // Messages queued up in the meantime are still sent, but
+                // lila may have missed earlier ones.
+                if backoff.succeeded() {
+                    log::warn!("reconnected to site-in ({} messages queued)", app.lila_queue.len() + batch.len());
+                    app.announce_state();
+                }
+
+                loop {
+                    if batch.is_empty() {
+                        app.lila_queue.pop_batch(batch_size, &mut batch);
+                    }
+
+                    for msg in &batch {
+                        log::trace!("site-in: {}", msg);
+                    }
+
+                    let res = if opt_inner.batch_frames && batch.len() > 1 {
+                        sink.send(&ipc::join_batch(&batch))
+                    } else {
+                        sink.send_batch(&batch)
+                    };
+
+                    match res {
+                        Ok(()) => batch.clear(),
+                        Err(err) => {
+                            log::error!("send to site-in failed: {}", err);
+                            backoff.wait();
+                            break;
+                        }
+                    }
                 }
             }
         }).unwrap();
@@ -764,48 +1741,98 @@ fn main() {
                     },
                 };
 
-                let mut write_guard = app.by_id.write();
-                if let Some(user_socket) = write_guard.get_mut(&socket_id) {
-                    user_socket.set_user(maybe_uid);
-                }
+                let events = app.by_id.write(&socket_id)
+                    .get_mut(&socket_id)
+                    .map(|user_socket| user_socket.set_user(maybe_uid));
+                app.publish_user_events(socket_id, events.into_iter().flatten());
             }
         }).unwrap();
 
+        // Threads for analysis, so that the event loop does not compute.
+        for analysis_recv in analysis_recvs {
+            s.builder().name("analysis worker".to_owned()).spawn(move |_| {
+                loop {
+                    analysis_recv.recv().expect("analysis recv").respond();
+                }
+            }).unwrap();
+        }
+
         // Thread for incoming messages from lila.
-        let opt_inner = opt.clone();
         let rate_limiter_inner = rate_limiter.clone();
-        s.builder().name("redis source".to_owned()).spawn(move |_| {
+        s.builder().name("lila source".to_owned()).spawn(move |_| {
             let mut rate_limiter = rate_limiter_inner;
+            let mut backoff = Backoff::new(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX);
 
-            let mut redis = redis::Client::open(opt_inner.redis.as_str())
-                .expect("redis open for subscribe")
-                .get_connection()
-                .expect("redis connection for subscribe");
+            loop {
+                let mut source = match transport.source() {
+                    Ok(source) => source,
+                    Err(err) => {
+                        log::error!("connection to site-out failed: {}", err);
+                        backoff.wait();
+                        continue;
+                    }
+                };
 
-            let mut incoming = redis.as_pubsub();
-            incoming.subscribe("site-out").expect("subscribe site-out");
+                if backoff.succeeded() {
+                    log::warn!("reconnected to site-out");
+                }
 
-            loop {
-                let msg = incoming.get_message()
-                    .expect("get message")
-                    .get_payload::<String>()
-                    .expect("get payload");
-
-                match LilaOut::parse(&msg) {
-                    Ok(msg) => {
-                        // Abuse this message as a tick, and stop tracking
-                        // IPs not seen for 60 seconds.
-                        if let LilaOut::MoveLatency(_) = msg {
-                            rate_limiter.cleanup(Duration::from_secs(60));
+                loop {
+                    let msg = match source.recv() {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::error!("receive from site-out failed: {}", err);
+                            backoff.wait();
+                            break;
+                        }
+                    };
+
+                    for msg in ipc::split_batch(&msg) {
+                        match LilaOut::parse(msg) {
+                            Ok(msg) => {
+                                // Abuse this message as a tick, and stop tracking
+                                // IPs not seen for 60 seconds.
+                                if let LilaOut::MoveLatency(_) = msg {
+                                    rate_limiter.cleanup(Duration::from_secs(60));
+                                }
+
+                                app.received(msg);
+                            },
+                            Err(err) => log::error!("invalid message from lila ({}): {}", err, msg),
                         }
+                    }
 
-                        app.received(msg);
-                    },
-                    Err(_) => log::error!("invalid message from lila: {}", msg),
+                    if let Err(err) = source.ack() {
+                        log::error!("ack to site-out failed: {}", err);
+                        backoff.wait();
+                        break;
+                    }
                 }
             }
         }).unwrap();
 
+        // Thread to notice when lila is down or does not answer asks.
+        let lila_timeout = Duration::from_secs(opt.lila_timeout);
+        s.builder().name("lila watchdog".to_owned()).spawn(move |_| {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                app.check_lila(lila_timeout);
+                app.expire_asks();
+                app.expire_replays();
+            }
+        }).unwrap();
+
+        // Thread to shut down gracefully.
+        let signals = Signals::new([signal_hook::SIGTERM]).expect("signal handler");
+        let shutdown_window = Duration::from_secs(opt.shutdown_window);
+        let reconnect_jitter = Duration::from_secs(opt.reconnect_jitter);
+        s.builder().name("signals".to_owned()).spawn(move |_| {
+            if signals.forever().next().is_some() {
+                app.drain(shutdown_window, reconnect_jitter);
+                std::process::exit(0);
+            }
+        }).unwrap();
+
         // Start websocket server.
         let mut settings = ws::Settings::default();
         settings.max_connections = opt.max_connections;
@@ -814,30 +1841,269 @@ fn main() {
         settings.in_buffer_grow = false;
 
         let mut socket_id = 0;
+        let ipv6_prefix = opt.rate_limiter_ipv6_prefix;
 
         let server = ws::Builder::new()
             .with_settings(settings)
-            .build(move |sender| {
+            .build(move |sender: Sender| {
                 socket_id += 1;
                 Socket {
                     app,
+                    outbox: Outbox::new(sender.clone(), &app.outbox_policy),
                     sender,
                     rate_limiter: rate_limiter.clone(),
+                    ipv6_prefix,
                     socket_id: SocketId(socket_id),
                     client_addr: None, // set during handshake
                     user_agent: None, // set during handshake
                     rate_limited_once: false,
+                    rejected: false,
+                    closing: false,
                     sri: None, // set during handshake
                     flag: None, // set during handshake
                     watching: HashSet::new(),
                     idle_timeout: None, // set during handshake
-                    log_ignore: false
+                    log_ignore: false,
+                    deflate: None, // set during handshake
                 }
             })
             .expect("valid settings");
 
         app.broadcaster.set(server.broadcaster()).expect("set broadcaster");
 
-        server.listen(&opt.bind).expect("ws listen");
+        let server = server.bind(&opt.bind).expect("ws bind");
+
+        // Thread for monitoring, on its own address, so that it is not
+        // exposed with the Websocket server. Started only once the
+        // Websocket server is bound, so that deploys can wait for it.
+        if let Some(ref metrics_bind) = opt.metrics_bind {
+            let listener = TcpListener::bind(metrics_bind).expect("metrics bind");
+            s.builder().name("metrics".to_owned()).spawn(move |_| {
+                for stream in listener.incoming() {
+                    let res = stream.and_then(|stream| serve_metrics(app, stream));
+                    if let Err(err) = res {
+                        log::warn!("metrics request failed: {}", err);
+                    }
+                }
+            }).unwrap();
+        }
+
+        server.run().expect("ws run");
     }).expect("scope");
 }
+
+/// Answer a single plain HTTP request for metrics.
+fn serve_metrics(app: &App, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split(' ').nth(1) {
+        Some("/metrics") => ("200 OK", app.metrics()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+#[allow(deprecated)] // ws still uses mio::channel
+mod tests {
+    use super::*;
+
+    fn test_app(lila_queue_size: usize, ipc_version: u8, node: Option<&str>) -> &'static App {
+        let (sid_sink, _) = channel::unbounded();
+        let (analysis_sink, _) = channel::bounded(1);
+        let analysis_sinks = vec![analysis_sink];
+        Box::leak(Box::new(App::new(
+            LilaQueue::new(lila_queue_size, OverflowPolicy::Drop, node.map(|node| node.parse().unwrap())),
+            ipc_version,
+            Duration::from_secs(5),
+            OutboxPolicy::new(u64::MAX, Duration::from_secs(10), u64::MAX),
+            None,
+            ReplaySettings { size: 10, retention: Duration::from_secs(60) },
+            ConnectionLimits { per_ip: 10, per_user: 10 },
+            "".parse().unwrap(),
+            sid_sink,
+            analysis_sinks)))
+    }
+
+    #[test]
+    fn test_announce_state_with_full_queue() {
+        let app = test_app(1, 0, None);
+        app.publish(LilaIn::DisconnectAll); // full, and lila is not reading
+
+        let (tx, _rx) = mio::channel::sync_channel(100);
+        let mut user_socket = UserSocket {
+            app,
+            sender: Outbox::new(Sender::new(Token(1), tx, 0), &app.outbox_policy),
+            sri: None,
+            join_since: None,
+            auth: SocketAuth::Requested,
+            pending_notified: false,
+            pending_following_onlines: false,
+        };
+
+        // Session lookup and lila sink reconnecting at the same time.
+        let (done, finished) = channel::unbounded();
+        let done_inner = done.clone();
+        thread::spawn(move || {
+            let events = user_socket.set_user(Some(UserId::new("foo").unwrap()));
+            app.publish_user_events(SocketId(1), events);
+            done_inner.send(user_socket).unwrap();
+        });
+        thread::spawn(move || {
+            app.announce_state();
+            drop(done);
+        });
+
+        let user_socket = finished.recv_timeout(Duration::from_secs(5)).expect("set user without deadlock");
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)).err(), Some(channel::RecvTimeoutError::Disconnected)); // announced
+        assert_eq!(app.lila_queue.len(), 1); // still full, the rest was dropped
+        assert_eq!(user_socket.user_id(), Some(&UserId::new("foo").unwrap()));
+    }
+
+    #[test]
+    fn test_hello_after_resync() {
+        let app = test_app(100, 2, None);
+        app.offer_hello();
+        app.received(LilaOut::Hello { node: None, version: 2 });
+        assert!(app.lila_asks.load(Ordering::Relaxed));
+
+        // Lila restarted, so negotiate again and use text meanwhile.
+        app.received(LilaOut::Resync);
+        assert!(!app.lila_asks.load(Ordering::Relaxed));
+
+        // Offered once more as soon as lila is listening.
+        app.received(LilaOut::MoveLatency(1));
+        app.received(LilaOut::MoveLatency(1));
+
+        let mut batch = Vec::new();
+        app.lila_queue.pop_batch(100, &mut batch);
+        assert_eq!(batch[0], "hello 2");
+        assert_eq!(batch[1], "hello 2");
+        assert!(batch[2..].iter().all(|msg| !msg.starts_with('{')));
+        assert_eq!(batch.iter().filter(|msg| *msg == "hello 2").count(), 3);
+    }
+
+    #[test]
+    fn test_user_messages_through_sri() {
+        let app = test_app(100, 0, None);
+        let sri: Sri = "abcdefgh".parse().unwrap();
+        let foo = UserId::new("foo").unwrap();
+        let mut replay = Replay::new(10);
+        replay.attach();
+        replay.attach();
+        app.replays.write(&sri).insert(sri.clone(), replay);
+        app.tell_sri(&sri, None, r#"{"t":"a"}"#);
+        app.tell_sri(&sri, Some(&foo), r#"{"t":"secret"}"#);
+
+        let (tx, rx) = mio::channel::sync_channel(100);
+        let user_socket = |token| {
+            let sender = Outbox::new(Sender::new(Token(token), tx.clone(), 0), &app.outbox_policy);
+            sender.set_resumable();
+            UserSocket {
+                app,
+                sender,
+                sri: Some(sri.clone()),
+                join_since: Some(0),
+                auth: SocketAuth::Requested,
+                pending_notified: false,
+                pending_following_onlines: false,
+            }
+        };
+
+        // Someone else presenting the sri of foo, after foo.
+        let mut other = user_socket(1);
+        let mut owner = user_socket(2);
+        assert!(rx.try_recv().is_err()); // nothing before authentication
+        let _events = owner.set_user(Some(foo.clone()));
+        let _events = other.set_user(Some(UserId::new("bar").unwrap()));
+
+        app.received(LilaOut::TellUsers { users: SmallVec::from_elem(foo.clone(), 1), payload: r#"{"t":"secret"}"# });
+        app.received(LilaOut::TellSri { sri: sri.clone(), payload: r#"{"t":"b"}"# });
+
+        let (other_received, owner_received): (Vec<_>, Vec<_>) = std::iter::from_fn(|| rx.try_recv().ok())
+            .partition(|command| command.token() == Token(1));
+        let other_received: Vec<String> = other_received.into_iter().map(|command| format!("{:?}", command.into_signal())).collect();
+        assert_eq!(other_received.len(), 2); // a and b
+        assert!(other_received.iter().all(|msg| !msg.contains("secret")));
+        assert_eq!(owner_received.len(), 4); // a, secret, secret and b
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        let app = test_app(100, 0, None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let get = |path: &str| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            serve_metrics(app, stream).unwrap();
+            let mut res = String::new();
+            io::Read::read_to_string(&mut client, &mut res).unwrap();
+            res
+        };
+
+        let res = get("/metrics");
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("lila_queue_length 0\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_connections_per_ip() {
+        let app = test_app(100, 0, None);
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        for _ in 0..10 {
+            assert!(app.accept_ip(ip, 64));
+        }
+        assert!(!app.accept_ip(ip, 64));
+        assert!(app.accept_ip("1.2.3.5".parse().unwrap(), 64));
+        assert_eq!(app.rejected_ip.load(Ordering::Relaxed), 1);
+
+        app.release_ip(ip, 64);
+        assert!(app.accept_ip(ip, 64));
+        for _ in 0..10 {
+            app.release_ip(ip, 64);
+        }
+        assert!(app.by_ip.read(&ip).get(&ip).is_none());
+
+        // Addresses of a single IPv6 client share a limit.
+        for i in 0..10 {
+            assert!(app.accept_ip(format!("2001:db8::{}", i + 1).parse().unwrap(), 64));
+        }
+        assert!(!app.accept_ip("2001:db8::ffff".parse().unwrap(), 64));
+        assert!(app.accept_ip("2001:db8:0:1::1".parse().unwrap(), 64));
+        assert_eq!(app.rejected_ip.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_replies_to_other_nodes() {
+        let app = test_app(100, 2, Some("a"));
+        let other = Some("b".parse().unwrap());
+        let us = Some("a".parse().unwrap());
+
+        app.received(LilaOut::Hello { node: other.clone(), version: 2 });
+        assert!(!app.lila_asks.load(Ordering::Relaxed));
+        app.received(LilaOut::Hello { node: us, version: 2 });
+        assert!(app.lila_asks.load(Ordering::Relaxed));
+
+        let uid = UserId::new("foo").unwrap();
+        app.ask(SocketId(1), "following_onlines", LilaIn::Friends(&uid));
+        app.received(LilaOut::Reply { node: other, id: 0, payload: "{}" });
+        assert!(app.asks.lock().contains_key(&0)); // still waiting
+        app.received(LilaOut::Reply { node: None, id: 0, payload: "{}" });
+        assert!(app.asks.lock().contains_key(&0)); // not addressed
+    }
+}
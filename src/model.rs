@@ -115,6 +115,12 @@ impl Sri {
     }
 }
 
+impl Serialize for Sri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for Sri {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let inner = ArrayString::deserialize(deserializer)?;
@@ -136,6 +142,52 @@ impl fmt::Display for Sri {
     }
 }
 
+/// Identifies this process among several lila-websocket instances.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NodeId(String);
+
+#[derive(Debug)]
+pub struct InvalidNodeId;
+
+impl fmt::Display for InvalidNodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid node id")
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = InvalidNodeId;
+
+    fn from_str(s: &str) -> Result<NodeId, InvalidNodeId> {
+        if !s.is_empty() && s.len() <= 32 &&
+           s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            Ok(NodeId(s.to_owned()))
+        } else {
+            Err(InvalidNodeId)
+        }
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let inner = String::deserialize(deserializer)?;
+        inner.parse().map_err(|_| serde::de::Error::custom("invalid node id"))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// Channels for server sent updates.
 #[derive(Deserialize, Debug, Copy, Clone)]
 pub enum Flag {
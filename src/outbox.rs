@@ -0,0 +1,306 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use ws::{CloseCode, Message, Sender};
+
+use crate::model::GameId;
+
+/// Close code for clients that do not read their messages.
+pub const SLOW_CONSUMER: CloseCode = CloseCode::Other(4008);
+
+/// Limits for messages that a client has not yet read, shared by all
+/// sockets.
+pub struct OutboxPolicy {
+    max_backlog: u64, // bytes
+    grace: Duration,
+    conflate_backlog: u64, // bytes
+    pub conflated: AtomicU64,
+    pub slow: AtomicU64,
+    pub dropped: AtomicU64,
+    pub disconnected: AtomicU64,
+}
+
+impl OutboxPolicy {
+    pub fn new(max_backlog: u64, grace: Duration, conflate_backlog: u64) -> OutboxPolicy {
+        OutboxPolicy {
+            max_backlog,
+            grace,
+            conflate_backlog,
+            conflated: AtomicU64::new(0),
+            slow: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            disconnected: AtomicU64::new(0),
+        }
+    }
+}
+
+struct State {
+    sent: AtomicU64, // bytes
+    acked: AtomicU64, // bytes the client has certainly read
+    pong_mark: AtomicU64, // end of the last pong
+    slow_since: Mutex<Option<Instant>>,
+    closing: AtomicBool,
+    resumable: AtomicBool, // gets user messages through the replay buffer of its sri
+    fens: Mutex<HashMap<GameId, Payload>>, // held back while behind
+}
+
+/// Message that is serialized once for all recipients.
+///
+/// This does not save copies: ws 0.9 takes an owned `Message` for each
+/// connection, so every recipient still gets its own copy of the text when
+/// it is sent. Only recipients that are behind get no copy.
+#[derive(Clone, Debug)]
+pub struct Payload(Arc<str>);
+
+impl Payload {
+    fn to_message(&self) -> Message {
+        Message::text(&*self.0)
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for Payload {
+    fn from(s: String) -> Payload {
+        Payload(s.into())
+    }
+}
+
+impl<'a> From<&'a str> for Payload {
+    fn from(s: &'a str) -> Payload {
+        Payload(s.into())
+    }
+}
+
+/// Sender that keeps track of how far behind the client is.
+///
+/// The ws event loop does not tell us when a message has actually been
+/// written, so the backlog is estimated from pings: clients send the next
+/// ping only after reading the pong to the previous one, so at that point
+/// everything up to the previous pong has been read.
+#[derive(Clone)]
+pub struct Outbox {
+    sender: Sender,
+    policy: &'static OutboxPolicy,
+    state: Arc<State>,
+}
+
+#[allow(clippy::result_large_err)] // ws::Result
+impl Outbox {
+    pub fn new(sender: Sender, policy: &'static OutboxPolicy) -> Outbox {
+        Outbox {
+            sender,
+            policy,
+            state: Arc::new(State {
+                sent: AtomicU64::new(0),
+                acked: AtomicU64::new(0),
+                pong_mark: AtomicU64::new(0),
+                slow_since: Mutex::new(None),
+                closing: AtomicBool::new(false),
+                resumable: AtomicBool::new(false),
+                fens: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn token(&self) -> ws::util::Token {
+        self.sender.token()
+    }
+
+    pub fn set_resumable(&self) {
+        self.state.resumable.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        self.state.resumable.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent but not yet known to be read by the client.
+    pub fn backlog(&self) -> u64 {
+        let sent = self.state.sent.load(Ordering::Relaxed);
+        sent.saturating_sub(self.state.acked.load(Ordering::Relaxed))
+    }
+
+    /// Send a message, unless the client is too far behind. Slow clients
+    /// are disconnected if they do not catch up within the grace period.
+    pub fn send<M: Into<Message>>(&self, msg: M) -> ws::Result<()> {
+        if self.backlog() > self.policy.max_backlog {
+            return self.drop_message();
+        }
+
+        self.push(msg.into()).map(|_| ())
+    }
+
+    /// Like `send`, for messages to many recipients.
+    pub fn send_shared(&self, payload: &Payload) -> ws::Result<()> {
+        if self.backlog() > self.policy.max_backlog {
+            return self.drop_message();
+        }
+
+        self.push(payload.to_message()).map(|_| ())
+    }
+
+    /// Send the position of a game. While the client is behind, only the
+    /// latest position of each game is kept and sent once it catches up.
+    pub fn send_fen(&self, game: &GameId, payload: &Payload) -> ws::Result<()> {
+        let mut fens = self.state.fens.lock();
+        if self.backlog() > self.policy.conflate_backlog {
+            if fens.insert(game.clone(), payload.clone()).is_some() {
+                self.policy.conflated.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok(());
+        }
+
+        if fens.remove(game).is_some() {
+            self.policy.conflated.fetch_add(1, Ordering::Relaxed);
+        }
+        self.send_shared(payload)
+    }
+
+    /// Answer a ping from the client. Pongs are never dropped, so that the
+    /// client can tell us that it caught up.
+    pub fn pong(&self) -> ws::Result<()> {
+        self.state.acked.fetch_max(self.state.pong_mark.load(Ordering::Relaxed), Ordering::Relaxed);
+        if self.backlog() <= self.policy.max_backlog {
+            self.state.slow_since.lock().take();
+        }
+
+        {
+            let mut fens = self.state.fens.lock();
+            if !fens.is_empty() && self.backlog() <= self.policy.conflate_backlog {
+                for (_, payload) in fens.drain() {
+                    self.send_shared(&payload)?;
+                }
+            }
+        }
+
+        let end = self.push(Message::text("0"))?;
+        self.state.pong_mark.store(end, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn close(&self, code: CloseCode) -> ws::Result<()> {
+        self.sender.close(code)
+    }
+
+    pub fn close_with_reason(&self, code: CloseCode, reason: &'static str) -> ws::Result<()> {
+        self.sender.close_with_reason(code, reason)
+    }
+
+    fn push(&self, msg: Message) -> ws::Result<u64> {
+        let len = msg.len() as u64;
+        let end = self.state.sent.fetch_add(len, Ordering::Relaxed) + len;
+        self.sender.send(msg)?;
+        Ok(end)
+    }
+
+    fn drop_message(&self) -> ws::Result<()> {
+        self.policy.dropped.fetch_add(1, Ordering::Relaxed);
+        self.on_slow()
+    }
+
+    fn on_slow(&self) -> ws::Result<()> {
+        let mut slow_since = self.state.slow_since.lock();
+        match *slow_since {
+            None => {
+                *slow_since = Some(Instant::now());
+                self.policy.slow.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(since) if since.elapsed() > self.policy.grace => {
+                if !self.state.closing.swap(true, Ordering::Relaxed) {
+                    log::info!("closing slow consumer ({} bytes behind)", self.backlog());
+                    self.policy.disconnected.fetch_add(1, Ordering::Relaxed);
+                    return self.sender.close_with_reason(SLOW_CONSUMER, "slow consumer");
+                }
+            }
+            Some(_) => (),
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for Outbox {
+    fn eq(&self, other: &Outbox) -> bool {
+        self.sender == other.sender
+    }
+}
+
+impl Eq for Outbox {}
+
+impl Hash for Outbox {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sender.hash(state)
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)] // ws still uses mio::channel
+mod tests {
+    use super::*;
+    use ws::util::Token;
+
+    fn policy(max_backlog: u64, conflate_backlog: u64) -> &'static OutboxPolicy {
+        Box::leak(Box::new(OutboxPolicy::new(max_backlog, Duration::from_secs(10), conflate_backlog)))
+    }
+
+    #[test]
+    fn test_conflate_fens() {
+        let policy = policy(1000, 0);
+        let (tx, rx) = mio::channel::sync_channel(100);
+        let outbox = Outbox::new(Sender::new(Token(1), tx, 0), policy);
+        let game: GameId = "abcdefgh".parse().unwrap();
+
+        outbox.send_fen(&game, &Payload::from("fen 1")).unwrap(); // sent
+        outbox.send_fen(&game, &Payload::from("fen 2")).unwrap(); // held back
+        outbox.send_fen(&game, &Payload::from("fen 3")).unwrap(); // replaces fen 2
+        assert_eq!(policy.conflated.load(Ordering::Relaxed), 1);
+
+        outbox.pong().unwrap(); // client read nothing yet
+        outbox.pong().unwrap(); // client read everything, flush fen 3
+        assert_eq!(outbox.backlog(), 6); // fen 3 and the last pong
+        assert_eq!(std::iter::from_fn(|| rx.try_recv().ok()).count(), 4);
+    }
+
+    #[test]
+    fn test_slow_consumer() {
+        let policy = Box::leak(Box::new(OutboxPolicy::new(5, Duration::from_millis(50), u64::MAX)));
+        let (tx, rx) = mio::channel::sync_channel(100);
+        let outbox = Outbox::new(Sender::new(Token(1), tx, 0), policy);
+
+        outbox.send("message").unwrap(); // now 7 bytes behind
+        outbox.send("dropped").unwrap(); // slow
+        assert_eq!(outbox.backlog(), 7);
+        assert_eq!(policy.slow.load(Ordering::Relaxed), 1);
+
+        outbox.pong().unwrap(); // client read nothing yet
+        outbox.pong().unwrap(); // client read everything, no longer slow
+        assert_eq!(outbox.backlog(), 1); // the last pong
+
+        outbox.send("message").unwrap();
+        outbox.send("dropped").unwrap(); // slow again
+        outbox.send("dropped").unwrap(); // within the grace period
+        assert_eq!(policy.slow.load(Ordering::Relaxed), 2);
+        assert_eq!(policy.disconnected.load(Ordering::Relaxed), 0);
+
+        std::thread::sleep(Duration::from_millis(60));
+        outbox.send("dropped").unwrap(); // closed
+        outbox.send("dropped").unwrap(); // closed only once
+        assert_eq!(policy.dropped.load(Ordering::Relaxed), 5);
+        assert_eq!(policy.disconnected.load(Ordering::Relaxed), 1);
+
+        let signals: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|command| format!("{:?}", command.into_signal()))
+            .collect();
+        assert_eq!(signals.len(), 5); // message, pong, pong, message, close
+        assert!(signals[4].starts_with("Close(Other(4008)"));
+    }
+}
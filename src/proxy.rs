@@ -0,0 +1,232 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Range of addresses, like `10.0.0.0/8`. A plain address is a range of one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug)]
+pub struct InvalidCidr;
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid cidr")
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Cidr, InvalidCidr> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap().parse().map_err(|_| InvalidCidr)?;
+        let max = max_prefix(&addr);
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or(InvalidCidr)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = v4_mask(self.prefix);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = v6_mask(self.prefix);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// IPv4 clients of dual stack listeners show up as IPv4-mapped IPv6
+/// addresses.
+fn canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+/// Key for rate limiting a client. IPv6 users typically get a whole prefix,
+/// so their addresses are grouped by the first `ipv6_prefix` bits. IPv4
+/// addresses stay separate.
+pub fn rate_limit_key(ip: &IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match canonical(ip) {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & v6_mask(ipv6_prefix))),
+        IpAddr::V4(v4) => IpAddr::V4(v4),
+    }
+}
+
+/// Reverse proxies whose `X-Forwarded-For` and `X-Real-IP` headers are
+/// believed. Parsed from a comma separated list of ranges.
+#[derive(Debug, Clone)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl FromStr for TrustedProxies {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<TrustedProxies, InvalidCidr> {
+        s.split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Address of the client, if the connection comes from `peer`, or
+    /// `None` if a proxy does not tell.
+    ///
+    /// Forwarding headers can be sent by anyone, so they are only used if
+    /// the peer is a trusted proxy. Then `X-Forwarded-For` or `Forwarded`
+    /// is read from the right, up to the first hop that is not trusted.
+    pub fn client_addr(&self, peer: IpAddr, x_forwarded_for: Option<&str>, forwarded: Option<&str>, x_real_ip: Option<&str>) -> Option<IpAddr> {
+        if !self.contains(&peer) {
+            return Some(canonical(&peer));
+        }
+
+        if let Some(x_forwarded_for) = x_forwarded_for {
+            return self.first_untrusted(x_forwarded_for.rsplit(',').map(|hop| hop.trim().parse().ok()));
+        }
+
+        if let Some(forwarded) = forwarded {
+            return self.first_untrusted(forwarded.rsplit(',').map(forwarded_for));
+        }
+
+        x_real_ip.and_then(|ip| ip.trim().parse().ok()).map(|ip| canonical(&ip))
+    }
+
+    /// Walk the hops from the right, up to the first one that is not
+    /// trusted.
+    fn first_untrusted<I: Iterator<Item = Option<IpAddr>>>(&self, hops: I) -> Option<IpAddr> {
+        let mut client = None;
+        for hop in hops {
+            match hop {
+                Some(ip) if self.contains(&ip) => client = Some(ip),
+                Some(ip) => return Some(canonical(&ip)),
+                None => break, // garbage from before the first trusted proxy
+            }
+        }
+        client.map(|ip| canonical(&ip))
+    }
+}
+
+/// Address in the `for` parameter of an element of a `Forwarded` header
+/// (RFC 7239), like `for=192.0.2.60;proto=http` or `for="[2001:db8::1]:4711"`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let node = element.split(';')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            if key.eq_ignore_ascii_case("for") { kv.next() } else { None }
+        })
+        .next()?
+        .trim()
+        .trim_matches('"');
+
+    match node.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?.parse().ok(),
+        None => node.split(':').next()?.parse().ok(), // without port
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"1.2.3.4".parse().unwrap()));
+        assert!("::1".parse::<Cidr>().unwrap().contains(&"::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_key() {
+        let key = |s: &str, prefix| rate_limit_key(&s.parse().unwrap(), prefix).to_string();
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 64), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:2:ffff::1", 64), "2001:db8:1:2::");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 48), "2001:db8:1::");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 128), "2001:db8:1:2:3:4:5:6");
+        assert_eq!(key("2001:db8:1:2:3:4:5:6", 0), "::");
+        assert_eq!(key("1.2.3.4", 64), "1.2.3.4");
+        assert_eq!(key("::ffff:1.2.3.4", 64), "1.2.3.4");
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let proxies: TrustedProxies = "127.0.0.0/8, 10.0.0.0/8".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Untrusted peers can not pretend to be someone else.
+        assert_eq!(proxies.client_addr(ip("1.2.3.4"), Some("5.6.7.8"), None, Some("5.6.7.8")), Some(ip("1.2.3.4")));
+
+        // Trusted proxies.
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), Some("5.6.7.8"), None, None), Some(ip("5.6.7.8")));
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), Some("6.6.6.6, 5.6.7.8, 10.0.0.2"), None, None), Some(ip("5.6.7.8")));
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), Some("garbage, 10.0.0.2"), None, None), Some(ip("10.0.0.2")));
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), Some("garbage"), None, None), None);
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), None, None, Some("5.6.7.8")), Some(ip("5.6.7.8")));
+
+        // Trusted proxies that do not tell.
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), None, None, None), None);
+
+        // No trusted proxies.
+        let proxies: TrustedProxies = "".parse().unwrap();
+        assert_eq!(proxies.client_addr(ip("127.0.0.1"), Some("5.6.7.8"), None, None), Some(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let proxies: TrustedProxies = "127.0.0.0/8, 10.0.0.0/8".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let client_addr = |forwarded| proxies.client_addr(ip("127.0.0.1"), None, Some(forwarded), None);
+
+        assert_eq!(client_addr("for=5.6.7.8"), Some(ip("5.6.7.8")));
+        assert_eq!(client_addr("for=6.6.6.6, For=\"5.6.7.8:4711\";proto=https, for=10.0.0.2"), Some(ip("5.6.7.8")));
+        assert_eq!(client_addr("proto=https;for=\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(client_addr("for=unknown"), None);
+        assert_eq!(client_addr("for=_hidden, for=10.0.0.2"), Some(ip("10.0.0.2")));
+    }
+}
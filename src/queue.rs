@@ -0,0 +1,352 @@
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::ipc;
+use crate::ipc::{Encoding, LilaIn};
+use crate::model::NodeId;
+
+/// What to do with messages to lila when the queue is full.
+///
+/// There is no policy that blocks until there is room: messages are
+/// published from the Websocket event loop, which must not stop, and the
+/// lila sink itself publishes when it reconnects, so it could wait for
+/// itself. Only `Drop` keeps the queue within its capacity. With the other
+/// policies, the capacity is a soft limit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Keep all messages.
+    Keep,
+    /// Merge stats (connections, lags) into queued messages of the same kind.
+    Coalesce,
+    /// Like coalesce, and also cancel queued connect/disconnect and
+    /// watch/unwatch pairs of the same user or game, and drop low priority
+    /// messages. If that is not enough, drop any message.
+    Drop,
+}
+
+#[derive(Debug)]
+pub struct UnknownOverflowPolicy;
+
+impl fmt::Display for UnknownOverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected keep, coalesce or drop")
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = UnknownOverflowPolicy;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy, UnknownOverflowPolicy> {
+        Ok(match s {
+            "keep" => OverflowPolicy::Keep,
+            "coalesce" => OverflowPolicy::Coalesce,
+            "drop" => OverflowPolicy::Drop,
+            _ => return Err(UnknownOverflowPolicy),
+        })
+    }
+}
+
+/// Messages that lila can do without, because they are just stats or the
+/// client can ask again (asks time out, so the client still gets an answer).
+fn is_low_priority(msg: &LilaIn) -> bool {
+    match msg {
+        LilaIn::Ask(_, msg) => is_low_priority(msg),
+        _ => matches!(msg,
+            LilaIn::Connections(_) | LilaIn::Lags(_) |
+            LilaIn::Notified(_) | LilaIn::Friends(_) | LilaIn::TellSri(..)),
+    }
+}
+
+/// Messages that change what lila knows about a user or game, and the
+/// message that undoes them.
+fn state_key(msg: &LilaIn) -> Option<(&'static str, &'static str, String)> {
+    match msg {
+        LilaIn::Connect(uid) => Some(("connect", "disconnect", uid.to_string())),
+        LilaIn::Disconnect(uid) => Some(("disconnect", "connect", uid.to_string())),
+        LilaIn::Watch(game) => Some(("watch", "unwatch", game.to_string())),
+        LilaIn::Unwatch(game) => Some(("unwatch", "watch", game.to_string())),
+        _ => None,
+    }
+}
+
+struct Queued {
+    tag: &'static str,
+    encoding: Encoding,
+    msg: String,
+    key: Option<String>, // of state messages
+    cancelled: bool,
+}
+
+/// Number of overflowing messages, by tag.
+#[derive(Default, Clone)]
+pub struct OverflowStats {
+    pub dropped: BTreeMap<&'static str, u64>,
+    pub coalesced: BTreeMap<&'static str, u64>,
+}
+
+impl OverflowStats {
+    fn is_empty(&self) -> bool {
+        self.dropped.is_empty() && self.coalesced.is_empty()
+    }
+}
+
+struct State {
+    queue: VecDeque<Queued>, // including cancelled messages
+    len: usize, // not cancelled
+    head_seq: u64, // of the front of the queue
+    state_msgs: HashMap<(&'static str, String), u64>, // seq of queued state messages
+    encoding: Encoding,
+    total: OverflowStats, // since startup
+    unreported: OverflowStats, // since last report
+}
+
+impl State {
+    fn count(stats: &mut BTreeMap<&'static str, u64>, unreported: &mut BTreeMap<&'static str, u64>, tag: &'static str) {
+        *stats.entry(tag).or_insert(0) += 1;
+        *unreported.entry(tag).or_insert(0) += 1;
+    }
+
+    fn count_coalesced(&mut self, tag: &'static str) {
+        State::count(&mut self.total.coalesced, &mut self.unreported.coalesced, tag);
+    }
+
+    fn count_dropped(&mut self, tag: &'static str) {
+        State::count(&mut self.total.dropped, &mut self.unreported.dropped, tag);
+    }
+
+    /// Cancel a queued message that undoes the new one.
+    fn cancel(&mut self, opposite: &'static str, key: String) -> bool {
+        match self.state_msgs.remove(&(opposite, key)) {
+            Some(seq) => {
+                self.queue[(seq - self.head_seq) as usize].cancelled = true;
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Queue of messages to lila.
+pub struct LilaQueue {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    node: Option<NodeId>,
+}
+
+impl LilaQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy, node: Option<NodeId>) -> LilaQueue {
+        LilaQueue {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                len: 0,
+                head_seq: 0,
+                state_msgs: HashMap::new(),
+                encoding: Encoding::Text,
+                total: OverflowStats::default(),
+                unreported: OverflowStats::default(),
+            }),
+            not_empty: Condvar::new(),
+            capacity,
+            policy,
+            node,
+        }
+    }
+
+    /// Queue a message, applying the overflow policy if the queue is full.
+    /// Never waits.
+    pub fn push(&self, msg: LilaIn) {
+        let tag = msg.tag();
+        let mut state = self.state.lock();
+
+        let key = state_key(&msg);
+
+        if state.len >= self.capacity && self.policy != OverflowPolicy::Keep {
+            let encoding = state.encoding;
+            if coalesce(&mut state.queue, &msg, encoding, self.node.as_ref()) {
+                state.count_coalesced(tag);
+                return;
+            }
+
+            if self.policy == OverflowPolicy::Drop {
+                if let Some((_, opposite, ref key)) = key {
+                    if state.cancel(opposite, key.clone()) {
+                        state.count_coalesced(opposite);
+                        state.count_coalesced(tag);
+                        return;
+                    }
+                }
+
+                // Low priority messages first, but the queue stays bounded.
+                if is_low_priority(&msg) || state.len >= self.capacity {
+                    state.count_dropped(tag);
+                    return;
+                }
+            }
+        }
+
+        let encoding = state.encoding;
+        let seq = state.head_seq + state.queue.len() as u64;
+        let key = key.map(|(_, _, key)| {
+            state.state_msgs.insert((tag, key.clone()), seq);
+            key
+        });
+        state.queue.push_back(Queued {
+            tag,
+            encoding,
+            msg: msg.encode(encoding, self.node.as_ref()),
+            key,
+            cancelled: false,
+        });
+        state.len += 1;
+        self.not_empty.notify_one();
+    }
+
+    /// Block until a message is available, then take up to `max` messages.
+    pub fn pop_batch(&self, max: usize, batch: &mut Vec<String>) {
+        let mut state = self.state.lock();
+        while state.len == 0 {
+            self.not_empty.wait(&mut state);
+        }
+
+        let start = batch.len();
+        while batch.len() - start < max {
+            let queued = match state.queue.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            let seq = state.head_seq;
+            state.head_seq += 1;
+            if queued.cancelled {
+                continue;
+            }
+            if let Some(key) = queued.key {
+                let entry = (queued.tag, key);
+                if state.state_msgs.get(&entry) == Some(&seq) {
+                    state.state_msgs.remove(&entry);
+                }
+            }
+            state.len -= 1;
+            batch.push(queued.msg);
+        }
+    }
+
+    /// Encode messages queued from now on as negotiated with lila.
+    pub fn set_encoding(&self, encoding: Encoding) {
+        self.state.lock().encoding = encoding;
+    }
+
+    pub fn node(&self) -> Option<&NodeId> {
+        self.node.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    pub fn total_stats(&self) -> OverflowStats {
+        self.state.lock().total.clone()
+    }
+
+    /// Log overflows since the last report.
+    pub fn report(&self) {
+        let (unreported, len) = {
+            let mut state = self.state.lock();
+            (mem::take(&mut state.unreported), state.len)
+        };
+        if !unreported.is_empty() {
+            log::warn!("lila queue overflow ({} queued): dropped {:?}, coalesced {:?}",
+                       len, unreported.dropped, unreported.coalesced);
+        }
+    }
+}
+
+/// Merge stats into a queued message of the same kind.
+fn coalesce(queue: &mut VecDeque<Queued>, msg: &LilaIn, encoding: Encoding, node: Option<&NodeId>) -> bool {
+    if !matches!(msg, LilaIn::Connections(_) | LilaIn::Lags(_)) {
+        return false;
+    }
+
+    let tag = msg.tag();
+
+    match queue.iter_mut().rev().find(|q| q.tag == tag && q.encoding == encoding && !q.cancelled) {
+        Some(queued) => {
+            if let LilaIn::Lags(lags) = msg {
+                ipc::merge_lags(&mut queued.msg, lags, encoding, node);
+            } else {
+                queued.msg = msg.encode(encoding, node);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::model::{GameId, UserId};
+
+    #[test]
+    fn test_coalesce() {
+        let queue = LilaQueue::new(2, OverflowPolicy::Drop, None);
+        let uid = UserId::new("foo").unwrap();
+        let mut lags = HashMap::new();
+        lags.insert(uid.clone(), 10);
+
+        queue.push(LilaIn::Lags(&lags));
+        queue.push(LilaIn::Connections(1));
+        queue.push(LilaIn::Connections(2));
+        lags.insert(uid.clone(), 20);
+        queue.push(LilaIn::Lags(&lags));
+        queue.push(LilaIn::Notified(&uid));
+
+        let stats = queue.total_stats();
+        assert_eq!(stats.coalesced.get("connections"), Some(&1));
+        assert_eq!(stats.coalesced.get("lags"), Some(&1));
+        assert_eq!(stats.dropped.get("notified"), Some(&1));
+
+        let mut batch = Vec::new();
+        queue.pop_batch(100, &mut batch);
+        assert_eq!(batch, vec!["lags foo:10,foo:20,", "connections 2"]);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let queue = LilaQueue::new(2, OverflowPolicy::Drop, None);
+        let foo = UserId::new("foo").unwrap();
+        let bar = UserId::new("bar").unwrap();
+        let game: GameId = "abcdefgh".parse().unwrap();
+
+        queue.push(LilaIn::Connect(&foo));
+        queue.push(LilaIn::Watch(&game));
+        queue.push(LilaIn::Disconnect(&foo)); // cancels connect foo
+        queue.push(LilaIn::Connect(&bar));
+        queue.push(LilaIn::Disconnect(&bar)); // cancels connect bar
+        queue.push(LilaIn::Connect(&foo));
+        queue.push(LilaIn::Connect(&bar)); // full
+        assert_eq!(queue.len(), 2);
+
+        let stats = queue.total_stats();
+        assert_eq!(stats.coalesced.get("connect"), Some(&2));
+        assert_eq!(stats.coalesced.get("disconnect"), Some(&2));
+        assert_eq!(stats.dropped.get("connect"), Some(&1));
+
+        let mut batch = Vec::new();
+        queue.pop_batch(100, &mut batch);
+        assert_eq!(batch, vec!["watch abcdefgh", "connect foo"]);
+
+        // Nothing left to cancel.
+        queue.push(LilaIn::Disconnect(&foo));
+        queue.push(LilaIn::Unwatch(&game));
+        queue.push(LilaIn::Watch(&game)); // cancels unwatch
+        assert_eq!(queue.len(), 1);
+    }
+}
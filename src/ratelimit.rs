@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ratelimit_meter::KeyedRateLimiter;
+
+use crate::model::UserId;
+
+/// Credits are given per 10 seconds.
+const PERIOD: Duration = Duration::from_secs(10);
+
+/// Separate budgets, so that clients using a lot of analysis can still
+/// ping and watch games.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Bucket {
+    Control,
+    Analysis,
+}
+
+/// Credits that each type of client message costs, like
+/// `anaMove=4,anaDests=2`. Other types cost 1.
+#[derive(Debug, Clone)]
+pub struct Costs(HashMap<String, u32>);
+
+#[derive(Debug)]
+pub struct InvalidCosts;
+
+impl fmt::Display for InvalidCosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid costs, expected type=credits,...")
+    }
+}
+
+impl FromStr for Costs {
+    type Err = InvalidCosts;
+
+    fn from_str(s: &str) -> Result<Costs, InvalidCosts> {
+        s.split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let mut kv = p.splitn(2, '=');
+                let tag = kv.next().unwrap().trim();
+                let cost = kv.next().and_then(|c| c.trim().parse().ok()).ok_or(InvalidCosts)?;
+                Ok((tag.to_owned(), cost))
+            })
+            .collect::<Result<_, _>>()
+            .map(Costs)
+    }
+}
+
+impl Costs {
+    pub fn get(&self, tag: &str) -> u32 {
+        self.0.get(tag).copied().unwrap_or(1)
+    }
+
+    pub fn max(&self) -> u32 {
+        self.0.values().copied().fold(1, u32::max)
+    }
+}
+
+/// Credits per 10 seconds in each bucket.
+#[derive(Debug, Copy, Clone)]
+pub struct Credits {
+    pub control: NonZeroU32,
+    pub analysis: NonZeroU32,
+}
+
+impl Credits {
+    pub fn min(&self) -> u32 {
+        u32::min(self.control.get(), self.analysis.get())
+    }
+}
+
+/// Who is charged for a message.
+pub enum Client<'a> {
+    Anonymous(IpAddr), // or rather the network, see proxy::rate_limit_key
+    User(&'a UserId),
+}
+
+#[derive(Clone)]
+struct Budget<K: Eq + Hash + Clone> {
+    control: KeyedRateLimiter<K>,
+    analysis: KeyedRateLimiter<K>,
+}
+
+impl<K: Eq + Hash + Clone> Budget<K> {
+    fn new(credits: Credits) -> Budget<K> {
+        Budget {
+            control: KeyedRateLimiter::new(credits.control, PERIOD),
+            analysis: KeyedRateLimiter::new(credits.analysis, PERIOD),
+        }
+    }
+
+    fn check(&mut self, key: K, bucket: Bucket, cost: u32) -> bool {
+        let limiter = match bucket {
+            Bucket::Control => &mut self.control,
+            Bucket::Analysis => &mut self.analysis,
+        };
+        limiter.check_n(key, cost).is_ok()
+    }
+
+    fn cleanup(&mut self, min_age: Duration) {
+        self.control.cleanup(min_age);
+        self.analysis.cleanup(min_age);
+    }
+}
+
+/// Rate limiter for client messages, with weighted costs. Authenticated
+/// users have their own budget, so that users behind a shared address are
+/// not limited together.
+#[derive(Clone)]
+pub struct RateLimiter {
+    ip: Budget<IpAddr>,
+    user: Budget<UserId>,
+    costs: Arc<Costs>,
+}
+
+impl RateLimiter {
+    pub fn new(ip_credits: Credits, user_credits: Credits, costs: Costs) -> RateLimiter {
+        RateLimiter {
+            ip: Budget::new(ip_credits),
+            user: Budget::new(user_credits),
+            costs: Arc::new(costs),
+        }
+    }
+
+    /// Take the credits for a message of type `tag`. Returns `false` if the
+    /// client has not enough left.
+    pub fn check(&mut self, client: Client<'_>, bucket: Bucket, tag: &str) -> bool {
+        let cost = self.costs.get(tag);
+        if cost == 0 {
+            return true;
+        }
+
+        match client {
+            Client::Anonymous(ip) => self.ip.check(ip, bucket, cost),
+            Client::User(uid) => self.user.check(uid.clone(), bucket, cost),
+        }
+    }
+
+    /// Stop tracking clients not seen for `min_age`.
+    pub fn cleanup(&mut self, min_age: Duration) {
+        self.ip.cleanup(min_age);
+        self.user.cleanup(min_age);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_costs() {
+        let costs: Costs = "anaMove=4, anaDests=2,p=0".parse().unwrap();
+        assert_eq!(costs.get("anaMove"), 4);
+        assert_eq!(costs.get("p"), 0);
+        assert_eq!(costs.get("startWatching"), 1);
+        assert_eq!(costs.max(), 4);
+
+        assert!("anaMove".parse::<Costs>().is_err());
+        assert!("anaMove=-1".parse::<Costs>().is_err());
+    }
+
+    fn credits(control: u32, analysis: u32) -> Credits {
+        Credits {
+            control: NonZeroU32::new(control).unwrap(),
+            analysis: NonZeroU32::new(analysis).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let costs: Costs = "anaMove=4".parse().unwrap();
+        let mut limiter = RateLimiter::new(credits(2, 10), credits(4, 20), costs);
+        let ip = || Client::Anonymous("1.2.3.4".parse().unwrap());
+
+        assert!(limiter.check(ip(), Bucket::Analysis, "anaMove"));
+        assert!(limiter.check(ip(), Bucket::Analysis, "anaMove"));
+        assert!(!limiter.check(ip(), Bucket::Analysis, "anaMove"));
+
+        // Pings still pass.
+        assert!(limiter.check(ip(), Bucket::Control, "p"));
+        assert!(limiter.check(ip(), Bucket::Control, "p"));
+        assert!(!limiter.check(ip(), Bucket::Control, "p"));
+
+        // Users have their own budget.
+        let uid = UserId::new("lichess").unwrap();
+        for _ in 0..4 {
+            assert!(limiter.check(Client::User(&uid), Bucket::Control, "p"));
+        }
+        assert!(!limiter.check(Client::User(&uid), Bucket::Control, "p"));
+    }
+}
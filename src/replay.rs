@@ -0,0 +1,146 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::model::UserId;
+use crate::outbox::Payload;
+
+/// Limits for replay buffers.
+#[derive(Debug, Copy, Clone)]
+pub struct ReplaySettings {
+    /// Number of messages kept for each sri.
+    pub size: usize,
+    /// How long to keep the buffer after the last socket of the sri closed.
+    pub retention: Duration,
+}
+
+/// Recent messages to an sri, so that clients can resume after
+/// reconnecting.
+///
+/// Messages are numbered from 1, and the number is added to each message as
+/// `seq`. Messages to the user of the sri are kept with the user id, so that
+/// they are only replayed to that user. The buffer is kept for a while after
+/// the last socket of the sri closed.
+pub struct Replay {
+    next_seq: u64,
+    messages: VecDeque<(u64, Option<UserId>, Payload)>,
+    capacity: usize,
+    sockets: usize,
+    detached_since: Option<Instant>,
+    pub uids: HashSet<UserId>, // users that authenticated on the sri
+}
+
+impl Replay {
+    pub fn new(capacity: usize) -> Replay {
+        Replay {
+            next_seq: 1,
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            sockets: 0,
+            detached_since: None,
+            uids: HashSet::new(),
+        }
+    }
+
+    /// Number the message to the sri, or to the user `to` through the sri,
+    /// and remember it, forgetting the oldest message if the buffer is full.
+    pub fn push(&mut self, to: Option<&UserId>, payload: &str) -> Payload {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let payload = Payload::from(with_seq(seq, payload));
+        if self.capacity > 0 {
+            if self.messages.len() >= self.capacity {
+                self.messages.pop_front();
+            }
+            self.messages.push_back((seq, to.cloned(), payload.clone()));
+        }
+        payload
+    }
+
+    /// Number of the last message, or 0.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Messages after `since` for a socket of the user `uid`. Returns `None`
+    /// if some of them are no longer available, or if `since` is not from
+    /// this buffer.
+    pub fn since<'a>(&'a self, since: u64, uid: Option<&'a UserId>) -> Option<impl Iterator<Item = &'a Payload>> {
+        let first = self.messages.front().map_or(self.next_seq, |&(seq, _, _)| seq);
+        if since < first - 1 || since >= self.next_seq {
+            return None;
+        }
+        Some(self.messages.iter()
+            .filter(move |(seq, to, _)| *seq > since && (to.is_none() || to.as_ref() == uid))
+            .map(|(_, _, payload)| payload))
+    }
+
+    pub fn attach(&mut self) {
+        self.sockets += 1;
+        self.detached_since = None;
+    }
+
+    pub fn detach(&mut self) {
+        self.sockets -= 1;
+        if self.sockets == 0 {
+            self.detached_since = Some(Instant::now());
+        }
+    }
+
+    /// No socket has been attached for longer than `retention`.
+    pub fn expired(&self, retention: Duration) -> bool {
+        self.detached_since.is_some_and(|since| since.elapsed() >= retention)
+    }
+}
+
+/// Add the sequence number to a JSON object.
+fn with_seq(seq: u64, payload: &str) -> String {
+    match payload.strip_prefix('{') {
+        Some(rest) if rest.trim_start().starts_with('}') => format!("{{\"seq\":{}{}", seq, rest),
+        Some(rest) => format!("{{\"seq\":{},{}", seq, rest),
+        None => payload.to_owned(), // not an object, can not be numbered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_seq() {
+        assert_eq!(with_seq(3, r#"{"t":"following_onlines","d":[]}"#), r#"{"seq":3,"t":"following_onlines","d":[]}"#);
+        assert_eq!(with_seq(4, "{}"), r#"{"seq":4}"#);
+        assert_eq!(with_seq(5, "0"), "0");
+    }
+
+    #[test]
+    fn test_since() {
+        let mut replay = Replay::new(2);
+        assert_eq!(replay.since(0, None).unwrap().count(), 0);
+        assert!(replay.since(1, None).is_none()); // from a different buffer
+
+        replay.push(None, r#"{"t":"a"}"#);
+        replay.push(None, r#"{"t":"b"}"#);
+        assert_eq!(replay.since(1, None).unwrap().count(), 1);
+
+        replay.push(None, r#"{"t":"c"}"#); // forgets a
+        assert!(replay.since(0, None).is_none());
+        let missed: Vec<String> = replay.since(1, None).unwrap().map(|p| p.to_string()).collect();
+        assert_eq!(missed, vec![r#"{"seq":2,"t":"b"}"#, r#"{"seq":3,"t":"c"}"#]);
+        assert_eq!(replay.since(3, None).unwrap().count(), 0);
+        assert_eq!(replay.last_seq(), 3);
+    }
+
+    #[test]
+    fn test_since_user() {
+        let foo = UserId::new("foo").unwrap();
+        let bar = UserId::new("bar").unwrap();
+        let mut replay = Replay::new(10);
+        replay.push(None, r#"{"t":"a"}"#);
+        replay.push(Some(&foo), r#"{"t":"b"}"#);
+
+        assert_eq!(replay.since(0, None).unwrap().count(), 1);
+        assert_eq!(replay.since(0, Some(&bar)).unwrap().count(), 1);
+        assert_eq!(replay.since(0, Some(&foo)).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,549 @@
+use std::fmt;
+use std::io;
+use std::io::{BufRead as _, BufReader, BufWriter, Write as _};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use std::collections::VecDeque;
+
+use crossbeam::channel;
+use redis::{ErrorKind, RedisResult, Value, from_redis_value};
+use redis::Commands as _;
+
+/// Ways to exchange messages with lila.
+#[derive(Debug, Copy, Clone)]
+pub enum TransportKind {
+    Redis,
+    RedisStreams,
+    Unix,
+    Memory,
+}
+
+#[derive(Debug)]
+pub struct UnknownTransportKind;
+
+impl fmt::Display for UnknownTransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected redis, redis-streams, unix or memory")
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = UnknownTransportKind;
+
+    fn from_str(s: &str) -> Result<TransportKind, UnknownTransportKind> {
+        Ok(match s {
+            "redis" => TransportKind::Redis,
+            "redis-streams" => TransportKind::RedisStreams,
+            "unix" => TransportKind::Unix,
+            "memory" => TransportKind::Memory,
+            _ => return Err(UnknownTransportKind),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Redis(redis::RedisError),
+    Io(io::Error),
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Redis(err) => err.fmt(f),
+            TransportError::Io(err) => err.fmt(f),
+            TransportError::Closed => f.write_str("connection closed"),
+        }
+    }
+}
+
+impl From<redis::RedisError> for TransportError {
+    fn from(err: redis::RedisError) -> TransportError {
+        TransportError::Redis(err)
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(err: io::Error) -> TransportError {
+        TransportError::Io(err)
+    }
+}
+
+/// Opens connections to lila. Each direction gets its own connection, so
+/// that they can be used (and reconnected) from different threads.
+pub trait Transport: Sync {
+    /// Connect for messages to lila (site-in).
+    fn sink(&self) -> Result<Box<dyn Sink>, TransportError>;
+    /// Connect for messages from lila (site-out).
+    fn source(&self) -> Result<Box<dyn Source>, TransportError>;
+}
+
+pub trait Sink {
+    fn send(&mut self, msg: &str) -> Result<(), TransportError>;
+
+    /// Send several messages, ideally without waiting for each one.
+    fn send_batch(&mut self, msgs: &[String]) -> Result<(), TransportError> {
+        for msg in msgs {
+            self.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait Source {
+    /// Block until the next message arrives.
+    fn recv(&mut self) -> Result<String, TransportError>;
+
+    /// Confirm that the last received message has been processed. Sources
+    /// with at-least-once delivery will otherwise deliver it again.
+    fn ack(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+}
+
+/// Redis pub/sub on the channels site-in and site-out.
+pub struct RedisTransport {
+    uri: String,
+}
+
+impl RedisTransport {
+    pub fn new(uri: &str) -> RedisTransport {
+        RedisTransport { uri: uri.to_owned() }
+    }
+}
+
+impl Transport for RedisTransport {
+    fn sink(&self) -> Result<Box<dyn Sink>, TransportError> {
+        let redis = redis::Client::open(self.uri.as_str())?.get_connection()?;
+        Ok(Box::new(RedisSink { redis }))
+    }
+
+    fn source(&self) -> Result<Box<dyn Source>, TransportError> {
+        let mut redis = redis::Client::open(self.uri.as_str())?.get_connection()?;
+        redis.as_pubsub().subscribe("site-out")?;
+        Ok(Box::new(RedisSource { redis }))
+    }
+}
+
+struct RedisSink {
+    redis: redis::Connection,
+}
+
+impl Sink for RedisSink {
+    fn send(&mut self, msg: &str) -> Result<(), TransportError> {
+        let ret: u32 = self.redis.publish("site-in", msg)?;
+        if ret == 0 {
+            log::error!("lila missed a message");
+        }
+        Ok(())
+    }
+
+    fn send_batch(&mut self, msgs: &[String]) -> Result<(), TransportError> {
+        let mut pipe = redis::pipe();
+        for msg in msgs {
+            pipe.cmd("PUBLISH").arg("site-in").arg(msg);
+        }
+        let rets: Vec<u32> = pipe.query(&mut self.redis)?;
+        let missed = rets.into_iter().filter(|ret| *ret == 0).count();
+        if missed > 0 {
+            log::error!("lila missed {} messages", missed);
+        }
+        Ok(())
+    }
+}
+
+struct RedisSource {
+    redis: redis::Connection, // subscribed to site-out
+}
+
+impl Source for RedisSource {
+    fn recv(&mut self) -> Result<String, TransportError> {
+        let mut incoming = self.redis.as_pubsub();
+        loop {
+            match incoming.get_message()?.get_payload() {
+                Ok(msg) => return Ok(msg),
+                Err(err) => log::error!("invalid payload from site-out: {}", err),
+            }
+        }
+    }
+}
+
+/// Redis streams site-in and site-out. Messages from lila are read with a
+/// consumer group, so that messages that were not acknowledged before a
+/// reconnect or restart are delivered again.
+pub struct RedisStreamTransport {
+    uri: String,
+    group: String,
+}
+
+/// Approximate length at which the site-in stream is trimmed.
+const STREAM_MAX_LEN: u32 = 100_000;
+
+/// Maximum number of entries to read from site-out at once.
+const STREAM_READ_COUNT: u32 = 100;
+
+impl RedisStreamTransport {
+    pub fn new(uri: &str, group: &str) -> RedisStreamTransport {
+        RedisStreamTransport {
+            uri: uri.to_owned(),
+            group: group.to_owned(),
+        }
+    }
+}
+
+impl Transport for RedisStreamTransport {
+    fn sink(&self) -> Result<Box<dyn Sink>, TransportError> {
+        let redis = redis::Client::open(self.uri.as_str())?.get_connection()?;
+        Ok(Box::new(RedisStreamSink { redis }))
+    }
+
+    fn source(&self) -> Result<Box<dyn Source>, TransportError> {
+        let mut redis = redis::Client::open(self.uri.as_str())?.get_connection()?;
+
+        let created: RedisResult<()> = redis::cmd("XGROUP")
+            .arg("CREATE").arg("site-out").arg(&self.group).arg("$").arg("MKSTREAM")
+            .query(&mut redis);
+        match created {
+            Err(ref err) if err.extension_error_code() == Some("BUSYGROUP") => (),
+            res => res?,
+        }
+
+        Ok(Box::new(RedisStreamSource {
+            redis,
+            group: self.group.clone(),
+            entries: VecDeque::new(),
+            reading_pending: true,
+            unacked: None,
+        }))
+    }
+}
+
+struct RedisStreamSink {
+    redis: redis::Connection,
+}
+
+impl Sink for RedisStreamSink {
+    fn send(&mut self, msg: &str) -> Result<(), TransportError> {
+        let _id: String = redis::cmd("XADD")
+            .arg("site-in").arg("MAXLEN").arg("~").arg(STREAM_MAX_LEN)
+            .arg("*").arg("msg").arg(msg)
+            .query(&mut self.redis)?;
+        Ok(())
+    }
+
+    fn send_batch(&mut self, msgs: &[String]) -> Result<(), TransportError> {
+        let mut pipe = redis::pipe();
+        for msg in msgs {
+            pipe.cmd("XADD")
+                .arg("site-in").arg("MAXLEN").arg("~").arg(STREAM_MAX_LEN)
+                .arg("*").arg("msg").arg(msg);
+        }
+        let _ids: Vec<String> = pipe.query(&mut self.redis)?;
+        Ok(())
+    }
+}
+
+struct RedisStreamSource {
+    redis: redis::Connection,
+    group: String,
+    entries: VecDeque<(String, StreamEntry)>,
+    reading_pending: bool, // first deliver entries that were never acknowledged
+    unacked: Option<String>,
+}
+
+impl RedisStreamSource {
+    fn xack(&mut self, id: &str) -> RedisResult<()> {
+        redis::cmd("XACK").arg("site-out").arg(&self.group).arg(id).query(&mut self.redis)
+    }
+}
+
+impl Source for RedisStreamSource {
+    fn recv(&mut self) -> Result<String, TransportError> {
+        loop {
+            match self.entries.pop_front() {
+                Some((id, StreamEntry::Msg(msg))) => {
+                    self.unacked = Some(id);
+                    return Ok(msg);
+                }
+                Some((id, StreamEntry::Trimmed)) | Some((id, StreamEntry::Invalid)) => {
+                    // Nothing to deliver, now or after a restart.
+                    self.xack(&id)?;
+                }
+                None => {
+                    let reply: Value = redis::cmd("XREADGROUP")
+                        .arg("GROUP").arg(&self.group).arg("lila-websocket")
+                        .arg("COUNT").arg(STREAM_READ_COUNT)
+                        .arg("BLOCK").arg(0)
+                        .arg("STREAMS").arg("site-out")
+                        .arg(if self.reading_pending { "0" } else { ">" })
+                        .query(&mut self.redis)?;
+
+                    let entries = stream_entries(&reply)?;
+                    if self.reading_pending && entries.is_empty() {
+                        self.reading_pending = false;
+                    }
+                    self.entries.extend(entries);
+                }
+            }
+        }
+    }
+
+    fn ack(&mut self) -> Result<(), TransportError> {
+        if let Some(id) = self.unacked.take() {
+            self.xack(&id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum StreamEntry {
+    Msg(String),
+    /// Pending entry that has been trimmed from the stream.
+    Trimmed,
+    /// Entry without a readable msg field. Logged when parsed.
+    Invalid,
+}
+
+/// Parse ids and msg fields from a reply to XREADGROUP. Entries are
+/// decoded one by one, so that a bad one can not block the others.
+fn stream_entries(reply: &Value) -> RedisResult<Vec<(String, StreamEntry)>> {
+    let streams: Vec<Value> = match reply {
+        Value::Nil => return Ok(Vec::new()),
+        reply => from_redis_value(reply)?,
+    };
+
+    let mut entries = Vec::new();
+    for stream in streams {
+        let (_name, items): (String, Vec<Value>) = from_redis_value(&stream)?;
+        for item in items {
+            let (id, fields): (String, Value) = from_redis_value(&item)?;
+            let entry = match fields {
+                Value::Nil => StreamEntry::Trimmed,
+                fields => match stream_msg(&fields) {
+                    Ok(msg) => StreamEntry::Msg(msg),
+                    Err(err) => {
+                        log::error!("invalid entry {} from site-out: {}", id, err);
+                        StreamEntry::Invalid
+                    }
+                },
+            };
+            entries.push((id, entry));
+        }
+    }
+    Ok(entries)
+}
+
+fn stream_msg(fields: &Value) -> RedisResult<String> {
+    let fields: Vec<(Vec<u8>, Vec<u8>)> = from_redis_value(fields)?;
+    let msg = fields.into_iter()
+        .find(|(k, _)| k == b"msg")
+        .map(|(_, v)| v)
+        .ok_or((ErrorKind::TypeError, "no msg field"))?;
+    String::from_utf8(msg).map_err(|_| (ErrorKind::TypeError, "msg is not utf-8").into())
+}
+
+/// Newline delimited messages over a Unix domain socket, where lila is
+/// listening. The first line of each connection names the channel
+/// (site-in or site-out), so that lila knows which direction it is for.
+pub struct UnixTransport {
+    path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new(path: PathBuf) -> UnixTransport {
+        UnixTransport { path }
+    }
+
+    fn connect(&self, channel: &str) -> Result<UnixStream, TransportError> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        writeln!(stream, "{}", channel)?;
+        Ok(stream)
+    }
+}
+
+impl Transport for UnixTransport {
+    fn sink(&self) -> Result<Box<dyn Sink>, TransportError> {
+        Ok(Box::new(UnixSink {
+            writer: BufWriter::new(self.connect("site-in")?),
+        }))
+    }
+
+    fn source(&self) -> Result<Box<dyn Source>, TransportError> {
+        Ok(Box::new(UnixSource {
+            reader: BufReader::new(self.connect("site-out")?),
+        }))
+    }
+}
+
+struct UnixSink {
+    writer: BufWriter<UnixStream>,
+}
+
+impl UnixSink {
+    fn write(&mut self, msg: &str) -> Result<(), TransportError> {
+        // Payloads are JSON, where line breaks can only be insignificant
+        // whitespace.
+        if msg.contains('\n') {
+            writeln!(self.writer, "{}", msg.replace('\n', " "))?;
+        } else {
+            writeln!(self.writer, "{}", msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for UnixSink {
+    fn send(&mut self, msg: &str) -> Result<(), TransportError> {
+        self.write(msg)?;
+        Ok(self.writer.flush()?)
+    }
+
+    fn send_batch(&mut self, msgs: &[String]) -> Result<(), TransportError> {
+        for msg in msgs {
+            self.write(msg)?;
+        }
+        Ok(self.writer.flush()?)
+    }
+}
+
+struct UnixSource {
+    reader: BufReader<UnixStream>,
+}
+
+impl Source for UnixSource {
+    fn recv(&mut self) -> Result<String, TransportError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(TransportError::Closed);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(line)
+    }
+}
+
+/// In-process channels, for tests and local development without lila.
+pub struct MemoryTransport {
+    to_lila: channel::Sender<String>,
+    from_lila: channel::Receiver<String>,
+}
+
+/// The other end of a `MemoryTransport`, standing in for lila.
+pub struct MemoryLila {
+    pub incoming: channel::Receiver<String>, // site-in
+    pub outgoing: channel::Sender<String>, // site-out
+}
+
+impl MemoryTransport {
+    pub fn new() -> (MemoryTransport, MemoryLila) {
+        let (to_lila, incoming) = channel::unbounded();
+        let (outgoing, from_lila) = channel::unbounded();
+        (MemoryTransport { to_lila, from_lila }, MemoryLila { incoming, outgoing })
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn sink(&self) -> Result<Box<dyn Sink>, TransportError> {
+        Ok(Box::new(MemorySink(self.to_lila.clone())))
+    }
+
+    fn source(&self) -> Result<Box<dyn Source>, TransportError> {
+        Ok(Box::new(MemorySource(self.from_lila.clone())))
+    }
+}
+
+struct MemorySink(channel::Sender<String>);
+
+impl Sink for MemorySink {
+    fn send(&mut self, msg: &str) -> Result<(), TransportError> {
+        self.0.send(msg.to_owned()).map_err(|_| TransportError::Closed)
+    }
+}
+
+struct MemorySource(channel::Receiver<String>);
+
+impl Source for MemorySource {
+    fn recv(&mut self) -> Result<String, TransportError> {
+        self.0.recv().map_err(|_| TransportError::Closed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_stream_entries() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                data("site-out"),
+                Value::Bulk(vec![
+                    Value::Bulk(vec![data("1-0"), Value::Bulk(vec![data("msg"), data("mlat 42")])]),
+                    Value::Bulk(vec![data("2-0"), Value::Nil]),
+                    Value::Bulk(vec![data("3-0"), Value::Bulk(vec![data("msg"), Value::Data(vec![0xff, 0xfe])])]),
+                    Value::Bulk(vec![data("4-0"), Value::Bulk(vec![data("other"), data("mlat 42")])]),
+                    Value::Bulk(vec![data("5-0"), Value::Bulk(vec![data("msg"), data("mlat 43")])]),
+                ]),
+            ]),
+        ]);
+
+        assert_eq!(stream_entries(&reply).unwrap(), vec![
+            ("1-0".to_owned(), StreamEntry::Msg("mlat 42".to_owned())),
+            ("2-0".to_owned(), StreamEntry::Trimmed),
+            ("3-0".to_owned(), StreamEntry::Invalid),
+            ("4-0".to_owned(), StreamEntry::Invalid),
+            ("5-0".to_owned(), StreamEntry::Msg("mlat 43".to_owned())),
+        ]);
+        assert!(stream_entries(&Value::Nil).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let (transport, lila) = MemoryTransport::new();
+
+        transport.sink().unwrap().send("connect foo").unwrap();
+        assert_eq!(lila.incoming.recv().unwrap(), "connect foo");
+
+        lila.outgoing.send("mlat 42".to_owned()).unwrap();
+        assert_eq!(transport.source().unwrap().recv().unwrap(), "mlat 42");
+    }
+
+    #[test]
+    fn test_unix_transport() {
+        let path = env::temp_dir().join(format!("lila-websocket-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let transport = UnixTransport::new(path.clone());
+
+        let lila = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            assert_eq!(lines.next().unwrap().unwrap(), "site-in");
+            assert_eq!(lines.next().unwrap().unwrap(), "tell/sri abc - {   \"t\": \"evalGet\"}");
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut channel = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut channel).unwrap();
+            assert_eq!(channel, "site-out\n");
+            stream.write_all(b"mlat 42\n").unwrap();
+        });
+
+        transport.sink().unwrap().send("tell/sri abc - {\n  \"t\": \"evalGet\"}").unwrap();
+        let mut source = transport.source().unwrap();
+        assert_eq!(source.recv().unwrap(), "mlat 42");
+        assert!(matches!(source.recv(), Err(TransportError::Closed)));
+
+        lila.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
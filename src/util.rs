@@ -3,6 +3,13 @@ use std::fmt::Display;
 use std::str::FromStr;
 use std::iter::FromIterator;
 use std::marker::PhantomData;
+use std::cmp::min;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde::{Deserializer, de};
 
 // adapted from: https://github.com/serde-rs/serde/issues/581#issuecomment-253626616
@@ -80,3 +87,75 @@ pub fn is_false(v: &bool) -> bool {
 pub fn is_zero_u8(v: &u8) -> bool {
     *v == 0
 }
+
+/// Exponential backoff for reconnecting to external services.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            current: min,
+            failures: 0,
+        }
+    }
+
+    /// Wait before the next attempt, doubling the delay each time.
+    pub fn wait(&mut self) {
+        self.failures += 1;
+        thread::sleep(self.current);
+        self.current = min(self.current * 2, self.max);
+    }
+
+    /// Report a successful attempt. Returns `true` if there were failures
+    /// since the last success, i.e. if this was a reconnect.
+    pub fn succeeded(&mut self) -> bool {
+        self.current = self.min;
+        mem::replace(&mut self.failures, 0) > 0
+    }
+}
+
+const SHARDS: usize = 64;
+
+/// Registry that is split into shards by key hash, so that unrelated keys
+/// do not contend for the same lock.
+pub struct Sharded<T> {
+    shards: Vec<RwLock<T>>,
+}
+
+impl<T: Default> Sharded<T> {
+    pub fn new() -> Sharded<T> {
+        Sharded {
+            shards: (0..SHARDS).map(|_| RwLock::new(T::default())).collect(),
+        }
+    }
+}
+
+impl<T> Sharded<T> {
+    fn shard<K: Hash + ?Sized>(&self, key: &K) -> &RwLock<T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Lock the shard responsible for `key` for reading.
+    pub fn read<K: Hash + ?Sized>(&self, key: &K) -> RwLockReadGuard<'_, T> {
+        self.shard(key).read()
+    }
+
+    /// Lock the shard responsible for `key` for writing.
+    pub fn write<K: Hash + ?Sized>(&self, key: &K) -> RwLockWriteGuard<'_, T> {
+        self.shard(key).write()
+    }
+
+    /// All shards, to be locked one at a time.
+    pub fn shards(&self) -> impl Iterator<Item = &RwLock<T>> {
+        self.shards.iter()
+    }
+}